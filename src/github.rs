@@ -1,52 +1,185 @@
-use std::{result::Result, error::Error};
+use std::{result::Result, error::Error, thread, time::Instant};
+use chrono::{Duration, Utc};
 
-use crate::{Credential, CredentialRequest, util};
+use crate::{parse_expiry, Credential, CredentialRequest, DeviceFlowConfig, util};
 
-use github_device_flow::DeviceFlow;
+use github_device_flow::{DeviceFlow, DeviceFlowState};
+use qrcode::{render::unicode, QrCode};
 
 pub fn device_flow_authorization_flow(config: CredentialRequest) -> Result<Credential, Box<dyn Error>> {
     let mut credential = Credential::empty();
 
-    let mut device_flow = DeviceFlow::new(&config.username, Some(config.host.clone().as_str()), None);
+    let host = config.auth_host();
+    let mut device_flow = DeviceFlow::new(&config.username, Some(host.as_str()), None);
     device_flow.setup();
 
     // // eprintln!("res is {:?}", config);
     eprintln!("Please visit {} in your browser", device_flow.verification_uri.as_ref().unwrap());
     eprintln!("And enter code: {}", device_flow.user_code.as_ref().unwrap());
 
-    let flow_result = device_flow.poll(20);
+    let settings = config.config.device_flow();
+    if settings.qr_code.unwrap_or(false) {
+        match qr_code(device_flow.verification_uri.as_ref().unwrap()) {
+            Ok(qr) => eprintln!("Or scan this code with your phone:\n{}", qr),
+            Err(e) => util::trace("login", format!("unable to render QR code: {}", e).as_str(), Some("login")),
+        }
+    }
+    if settings.open_browser.unwrap_or(false) && util::open_in_browser(device_flow.verification_uri.as_ref().unwrap()) {
+        eprintln!("Opened the verification page in your browser");
+    }
+    if settings.copy_code.unwrap_or(false) && util::copy_to_clipboard(device_flow.user_code.as_ref().unwrap()) {
+        eprintln!("Copied the code to your clipboard");
+    }
 
-    match flow_result {
-        Ok(cred) => {
-            credential.token = cred.token;
-            credential.refresh_token = cred.refresh_token;
-            credential.expiry = cred.expiry;
-        },
+    let cred = poll_for_authorization(&mut device_flow, &settings)?;
+    credential.token = cred.token;
+    credential.refresh_token = cred.refresh_token;
+    credential.expiry = parse_expiry(&cred.expiry);
+    // The device flow crate doesn't expose `refresh_token_expires_in`.
+    credential.set_refresh_token_issued_now(None);
+
+    match fetch_login(&host, &credential.token) {
+        Ok(login) => credential.login = login,
         Err(e) => {
-            eprintln!("Error: {:?}", e);
-            return Err(util::credential_error("Error during device flow authorization"))
+            let err = format!("Unable to look up the authenticated user: {}", e);
+            util::trace("login", err.as_str(), Some("login"));
         }
     }
 
     Ok(credential)
 }
 
+/// Render `url` as a QR code of Unicode half blocks, two rows per line, with
+/// the colours inverted to read as dark on light on a dark terminal.
+fn qr_code(url: &str) -> Result<String, Box<dyn Error>> {
+    let code = QrCode::new(url.as_bytes())?;
+    Ok(code
+        .render::<unicode::Dense1x2>()
+        .dark_color(unicode::Dense1x2::Light)
+        .light_color(unicode::Dense1x2::Dark)
+        .build())
+}
+
+/// Poll until the user authorizes the device, giving up after the configured
+/// timeout or number of attempts.
+fn poll_for_authorization(device_flow: &mut DeviceFlow, settings: &DeviceFlowConfig) -> Result<github_device_flow::Credential, Box<dyn Error>> {
+    let started = Instant::now();
+    let mut attempts = 0;
+
+    loop {
+        attempts += 1;
+        // Each poll makes one token request and then sleeps for the server's
+        // interval, which it lengthens when told to slow down.
+        let server_interval = match device_flow.poll(1) {
+            Ok(cred) => return Ok(cred),
+            Err(e) => match device_flow.state {
+                DeviceFlowState::Processing(interval) => interval,
+                _ => {
+                    eprintln!("Error: {:?}", e);
+                    return Err(util::credential_error("Error during device flow authorization"))
+                }
+            }
+        };
+        let trace_msg = format!("authorization pending after attempt {}, server interval {:?}", attempts, server_interval);
+        util::trace("login", trace_msg.as_str(), Some("login"));
+
+        let extra_wait = settings.interval().saturating_sub(server_interval);
+        if settings.max_attempts.is_some_and(|max| attempts >= max) {
+            let err = format!("Timed out waiting for authorization after {} attempts", attempts);
+            return Err(util::credential_error(err.as_str()))
+        }
+        if started.elapsed() + extra_wait >= settings.timeout() {
+            let err = format!("Timed out waiting for authorization after {} seconds", started.elapsed().as_secs());
+            return Err(util::credential_error(err.as_str()))
+        }
+        thread::sleep(extra_wait);
+    }
+}
+
+fn api_url(host: &str) -> String {
+    if host == "github.com" {
+        String::from("https://api.github.com")
+    } else if host.ends_with(".ghe.com") {
+        format!("https://api.{}", host)
+    } else {
+        format!("https://{}/api/v3", host)
+    }
+}
+
+/// Look up the login of the user a token belongs to.
+pub fn fetch_login(host: &str, token: &str) -> Result<String, Box<dyn Error>> {
+    let response = reqwest::blocking::Client::new()
+        .get(format!("{}/user", api_url(host)))
+        .header("Accept", "application/vnd.github+json")
+        .header("Authorization", format!("Bearer {}", token))
+        .header("User-Agent", "git-credential-github-keychain")
+        .send()?
+        .error_for_status()?;
+
+    let user: serde_json::Value = serde_json::from_str(&response.text()?)?;
+    match user["login"].as_str() {
+        Some(login) => Ok(String::from(login)),
+        None => Err(util::credential_error("No login in user response")),
+    }
+}
+
+/// The fields of GitHub's OAuth token response used when refreshing. It
+/// holds tokens, so it deliberately doesn't implement `Debug`.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    expires_in: Option<i64>,
+    refresh_token: Option<String>,
+    refresh_token_expires_in: Option<i64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Exchange a refresh token for new tokens. This calls GitHub directly
+/// rather than `github_device_flow::refresh`, which drops the
+/// `refresh_token_expires_in` of the response.
+fn request_refresh(host: &str, client_id: &str, refresh_token: &str) -> Result<TokenResponse, Box<dyn Error>> {
+    let response = reqwest::blocking::Client::new()
+        .post(format!("https://{}/login/oauth/access_token", host))
+        .header("Accept", "application/json")
+        .header("User-Agent", "git-credential-github-keychain")
+        .form(&[("client_id", client_id), ("grant_type", "refresh_token"), ("refresh_token", refresh_token)])
+        .send()?
+        .error_for_status()?;
+
+    let token: TokenResponse = serde_json::from_str(&response.text()?)?;
+    if let Some(error) = &token.error {
+        let err = format!("{}: {}", error, token.error_description.clone().unwrap_or_default());
+        return Err(util::credential_error(err.as_str()))
+    }
+    Ok(token)
+}
+
 pub fn refresh_credential(credential: &mut Credential, config: &mut CredentialRequest) -> Result<Credential, Box<dyn Error>> {
     if !config.is_configured() {
         return Err(util::credential_error("Credential request is not associated to an App Config"))
     }
-    let refresh_result = github_device_flow::refresh(&config.username, &credential.refresh_token, Some(config.host.clone()), None);
+    let refresh_result = request_refresh(&config.auth_host(), &config.username, &credential.refresh_token);
 
     match refresh_result {
-        Ok(cred) => {
-            credential.token = cred.token;
-            credential.refresh_token = cred.refresh_token;
-            credential.expiry = cred.expiry;
+        Ok(TokenResponse { access_token: Some(token), expires_in, refresh_token, refresh_token_expires_in, .. }) => {
+            credential.token = token;
+            credential.expiry = expires_in.map(|seconds| Utc::now() + Duration::seconds(seconds));
+            if let Some(refresh_token) = refresh_token {
+                credential.refresh_token = refresh_token;
+                credential.set_refresh_token_issued_now(refresh_token_expires_in);
+            }
             Ok(credential.clone())
         },
+        // Errors GitHub reports are returned by `request_refresh`, and the
+        // response itself isn't printed since it may hold a refresh token.
+        Ok(_) => {
+            eprintln!("Error: no access token in the refresh response");
+            Err(util::credential_error("Error refreshing access token"))
+        },
         Err(e) => {
             eprintln!("Error: {:?}", e);
-            return Err(util::credential_error("Error during device flow authorization"))
+            Err(util::credential_error("Error refreshing access token"))
         }
     }
 }
@@ -7,11 +7,11 @@ pub mod util;
 pub mod storage;
 
 use std::{fmt, error::Error};
-use chrono::{DateTime};
+use chrono::{DateTime, Duration};
 use chrono::offset::Utc;
 
-#[cfg(target_family = "unix")]
 use std::fs;
+#[cfg(target_family = "unix")]
 use std::os::unix::fs::PermissionsExt;
 
 #[derive(Debug)]
@@ -24,12 +24,22 @@ impl fmt::Display for CredentialError {
 
 impl Error for CredentialError {}
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+/// How long GitHub App refresh tokens are valid for, 6 months.
+pub const REFRESH_TOKEN_LIFETIME_SECONDS: i64 = 15_811_200;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct Credential {
     pub client_id: String,
     pub token: String,
-    pub expiry: String,
+    #[serde(with = "expiry_format")]
+    pub expiry: Option<DateTime<Utc>>,
     pub refresh_token: String,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default)]
+    pub login: String,
+    #[serde(default, with = "expiry_format")]
+    pub refresh_token_expiry: Option<DateTime<Utc>>,
 }
 
 impl Credential {
@@ -37,18 +47,77 @@ impl Credential {
         Credential {
             client_id: String::new(),
             token: String::new(),
-            expiry: String::new(),
+            expiry: None,
             refresh_token: String::new(),
+            host: String::new(),
+            login: String::new(),
+            refresh_token_expiry: None,
         }
     }
 
+    /// Whether this credential was issued to `client_id` on `host`, and to
+    /// the account `login` when one is given.
+    fn belongs_to(&self, host: &str, client_id: &str, login: &str) -> bool {
+        self.host == host && self.client_id == client_id && (login.is_empty() || self.login == login)
+    }
+
     fn is_expired(&self) -> bool {
-        let exp = match DateTime::parse_from_rfc3339(self.expiry.as_str()) {
-            Ok(time) => time,
-            Err(_) => return false
-        };
-        let now = Utc::now();
-        now > exp
+        self.expires_within(Duration::zero())
+    }
+
+    /// Whether this credential expires within `margin` from now. A token
+    /// issued alongside a refresh token always expires, so when its expiry is
+    /// missing it is treated as expired and refreshed. Tokens without a
+    /// refresh token and without an expiry never expire.
+    fn expires_within(&self, margin: Duration) -> bool {
+        match self.expiry {
+            Some(expiry) => expires_within(expiry, margin),
+            None => !self.refresh_token.is_empty(),
+        }
+    }
+
+    /// Whether the refresh token expires within `margin` from now. Refresh
+    /// tokens stored before their expiry was tracked never expire.
+    fn refresh_token_expires_within(&self, margin: Duration) -> bool {
+        self.refresh_token_expiry.is_some_and(|expiry| expires_within(expiry, margin))
+    }
+
+    /// Record a newly issued refresh token's expiry from the response's
+    /// `refresh_token_expires_in`. Where that isn't available, e.g. for new
+    /// logins as the device flow crate doesn't expose it, GitHub's default of
+    /// `REFRESH_TOKEN_LIFETIME_SECONDS` is assumed.
+    fn set_refresh_token_issued_now(&mut self, expires_in: Option<i64>) {
+        if self.refresh_token.is_empty() {
+            self.refresh_token_expiry = None;
+        } else {
+            let expires_in = expires_in.unwrap_or(REFRESH_TOKEN_LIFETIME_SECONDS);
+            self.refresh_token_expiry = Some(Utc::now() + Duration::seconds(expires_in));
+        }
+    }
+
+    /// The expiry as seconds since the Unix epoch, as git's
+    /// `password_expiry_utc` expects.
+    pub fn expiry_timestamp(&self) -> Option<i64> {
+        self.expiry.map(|exp| exp.timestamp())
+    }
+
+    /// Apply the attributes git handed back during `store` to this credential.
+    fn update_from_request(&mut self, request: &CredentialRequest) {
+        if !request.password.is_empty() {
+            self.token = request.password.clone();
+        }
+        if let Ok(seconds) = request.password_expiry_utc.parse::<i64>() {
+            // git echoes back the expiry we reported, which drops sub-second precision.
+            if self.expiry_timestamp() != Some(seconds) {
+                if let Some(expiry) = DateTime::from_timestamp(seconds, 0) {
+                    self.expiry = Some(expiry);
+                }
+            }
+        }
+        if !request.oauth_refresh_token.is_empty() && request.oauth_refresh_token != self.refresh_token {
+            self.refresh_token = request.oauth_refresh_token.clone();
+            self.set_refresh_token_issued_now(None);
+        }
     }
 }
 
@@ -59,20 +128,36 @@ pub struct CredentialRequest {
     protocol: String,
     pub path: String,
     port: String,
+    pub login: String,
+    pub password: String,
+    pub password_expiry_utc: String,
+    pub oauth_refresh_token: String,
     config: GithubKeychainConfig,
 }
 
 impl CredentialRequest {
+    #[cfg(test)]
     fn empty() -> CredentialRequest {
-        let cfg: GithubKeychainConfig = confy::load("github-keychain", None).unwrap();
+        CredentialRequest::with_config(GithubKeychainConfig::default())
+    }
 
+    /// An empty request carrying the configuration stored on disk.
+    pub fn load() -> Result<CredentialRequest, Box<dyn Error>> {
+        Ok(CredentialRequest::with_config(GithubKeychainConfig::load()?))
+    }
+
+    fn with_config(config: GithubKeychainConfig) -> CredentialRequest {
         CredentialRequest {
             username: String::new(),
             host: String::from("github.com"),
             protocol: String::new(),
             path: String::new(),
             port: String::new(),
-            config: cfg,
+            login: String::new(),
+            password: String::new(),
+            password_expiry_utc: String::new(),
+            oauth_refresh_token: String::new(),
+            config,
         }
     }
 
@@ -92,7 +177,58 @@ impl CredentialRequest {
         }
     }
 
-    pub fn delete_credential(&self) -> Result<(), confy::ConfyError> {
+    /// Pick the account to use: the `username=` git sent when it is not a
+    /// client ID, otherwise the `login` of the matching `AppConfig`.
+    fn resolve_login(&mut self) {
+        let app_config = match self.app_config() {
+            Some(ac) => ac,
+            None => return,
+        };
+
+        let is_client_id = self.config.config_for_client_id(&self.username).is_some();
+        if !self.username.is_empty() && self.username != "x-oauth-token" && !is_client_id {
+            self.login = std::mem::take(&mut self.username);
+        } else if let Some(login) = app_config.login {
+            self.login = login;
+        }
+    }
+
+    /// The host whose OAuth endpoints issue credentials for this request,
+    /// taken from the matching `AppConfig` when it names one.
+    pub fn auth_host(&self) -> String {
+        match self.app_config().and_then(|ac| ac.host) {
+            Some(host) => host,
+            None => String::from(auth_host(&self.host)),
+        }
+    }
+
+    /// The attributes of this request in git's credential helper input format.
+    pub fn attributes(&self) -> String {
+        let mut attributes = String::new();
+        let pairs = [
+            ("protocol", &self.protocol),
+            ("host", &self.host),
+            ("path", &self.path),
+            ("port", &self.port),
+            ("username", &self.username),
+            ("password", &self.password),
+            ("password_expiry_utc", &self.password_expiry_utc),
+            ("oauth_refresh_token", &self.oauth_refresh_token),
+        ];
+        for (name, value) in pairs {
+            if !value.is_empty() {
+                attributes.push_str(&format!("{}={}\n", name, value));
+            }
+        }
+        attributes
+    }
+
+    /// Apply `login`'s device flow flags for this run, they are not saved.
+    pub fn override_device_flow(&mut self, overrides: &DeviceFlowConfig) {
+        self.config.override_device_flow(overrides);
+    }
+
+    pub fn delete_credential(&self) -> Result<(), Box<dyn Error>> {
         let mut conf = self.config.clone();
         conf.delete_credential(self)
     }
@@ -108,100 +244,443 @@ impl fmt::Display for CredentialRequest {
 pub struct AppConfig {
     pub path: String,
     pub client_id: String,
+    pub host: Option<String>,
+    pub login: Option<String>,
+}
+
+impl AppConfig {
+    /// How specifically this config matches a request, higher is more specific.
+    /// `path` may be a literal, a glob (`acme-*`, `*/infra-*`) or a regex
+    /// starting with `^`, which is always anchored at both ends. A config with
+    /// a `host` only matches that host and beats an otherwise equal match
+    /// without one.
+    fn match_rank(&self, owner: &str, repo: &str, host: &str) -> Option<u8> {
+        match &self.host {
+            Some(config_host) if !config_host.eq_ignore_ascii_case(auth_host(host)) => None,
+            Some(_) => self.path_rank(owner, repo, host).map(|rank| rank * 2 + 1),
+            None => self.path_rank(owner, repo, host).map(|rank| rank * 2),
+        }
+    }
+
+    fn path_rank(&self, owner: &str, repo: &str, host: &str) -> Option<u8> {
+        let full_path = format!("{}/{}", owner, repo);
+
+        if self.is_pattern() {
+            if !repo.is_empty() && self.pattern_matches(&full_path) {
+                Some(5)
+            } else if !owner.is_empty() && self.pattern_matches(owner) {
+                Some(3)
+            } else {
+                None
+            }
+        } else if !repo.is_empty() && self.path.eq_ignore_ascii_case(&full_path) {
+            Some(6)
+        } else if !owner.is_empty() && self.path.eq_ignore_ascii_case(owner) {
+            Some(4)
+        } else if self.path.eq_ignore_ascii_case(host) {
+            Some(2)
+        } else if self.path == "default" && (self.host.is_some() || is_github_host(host)) {
+            Some(1)
+        } else {
+            None
+        }
+    }
+
+    fn is_pattern(&self) -> bool {
+        self.path.starts_with('^') || self.path.contains(['*', '?', '['])
+    }
+
+    fn pattern_matches(&self, candidate: &str) -> bool {
+        if self.path.starts_with('^') {
+            let anchored = format!("^(?i:{})$", self.path.trim_start_matches('^').trim_end_matches('$'));
+            match regex::Regex::new(&anchored) {
+                Ok(re) => re.is_match(candidate),
+                Err(e) => {
+                    util::trace("config", format!("invalid regex path={}: {}", self.path, e).as_str(), Some("config"));
+                    false
+                }
+            }
+        } else {
+            let options = glob::MatchOptions {
+                case_sensitive: false,
+                require_literal_separator: true,
+                require_literal_leading_dot: false,
+            };
+            match glob::Pattern::new(&self.path) {
+                Ok(pattern) => pattern.matches_with(candidate, options),
+                Err(e) => {
+                    util::trace("config", format!("invalid glob path={}: {}", self.path, e).as_str(), Some("config"));
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// How the device flow is presented and how long `login` waits for it to be
+/// authorized, from the `[device_flow]` table of the configuration file or
+/// `login`'s flags.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DeviceFlowConfig {
+    pub timeout_seconds: Option<u64>,
+    pub interval_seconds: Option<u64>,
+    pub max_attempts: Option<u32>,
+    pub open_browser: Option<bool>,
+    pub copy_code: Option<bool>,
+    pub qr_code: Option<bool>,
+}
+
+impl DeviceFlowConfig {
+    /// How long to wait in total. Defaults to 15 minutes, the lifetime of a
+    /// GitHub device code.
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout_seconds.unwrap_or(900))
+    }
+
+    /// The least time between polls. The server's interval, raised by any
+    /// `slow_down` responses, is used when it is longer.
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_seconds.unwrap_or(0))
+    }
+
+    /// Settings given in `overrides` take precedence over these.
+    pub fn merge(&self, overrides: &DeviceFlowConfig) -> DeviceFlowConfig {
+        DeviceFlowConfig {
+            timeout_seconds: overrides.timeout_seconds.or(self.timeout_seconds),
+            interval_seconds: overrides.interval_seconds.or(self.interval_seconds),
+            max_attempts: overrides.max_attempts.or(self.max_attempts),
+            open_browser: overrides.open_browser.or(self.open_browser),
+            copy_code: overrides.copy_code.or(self.copy_code),
+            qr_code: overrides.qr_code.or(self.qr_code),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GithubKeychainConfig {
     version: u8,
     fallback: String,
+    fallbacks: Option<Vec<String>>,
+    storage_backends: Option<Vec<String>>,
+    storage_policy: Option<String>,
+    encryption_key_file: Option<String>,
+    pass_command: Option<String>,
+    pass_prefix: Option<String>,
+    access_token_cache: Option<String>,
+    refresh_margin_seconds: Option<i64>,
+    refresh_token_warning_days: Option<i64>,
+    non_interactive: Option<bool>,
+    device_flow: Option<DeviceFlowConfig>,
     app_configs: Option<Vec<AppConfig>>,
     credentials: Option<Vec<Credential>>,
 }
 
+/// The schema version written by this build. Version 1 added `host` and
+/// `login` to stored credentials.
+pub const CONFIG_VERSION: u8 = 1;
+
 impl GithubKeychainConfig {
+    /// Load the configuration file, upgrading older schemas in place after
+    /// writing a backup of the original file.
+    pub fn load() -> Result<GithubKeychainConfig, Box<dyn Error>> {
+        let path = confy::get_configuration_file_path("github-keychain", None)?;
+
+        let on_disk_version = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let value: toml::Value = toml::from_str(&contents)?;
+                let version = value.get("version").and_then(|v| v.as_integer()).unwrap_or(0);
+                check_version(version)?;
+                Some(version)
+            },
+            Err(_) => None,
+        };
+
+        let mut config: GithubKeychainConfig = confy::load("github-keychain", None)?;
+
+        if on_disk_version.is_some() && config.version < CONFIG_VERSION {
+            let backup = path.with_extension(format!("toml.v{}.bak", config.version));
+            fs::copy(&path, &backup)?;
+            #[cfg(unix)]
+            fs::set_permissions(&backup, fs::Permissions::from_mode(0o600))?;
+            util::trace("config", format!("backed up config to {}", backup.display()).as_str(), Some("config"));
+
+            config = update_config(|config| config.migrate())?;
+            util::trace("config", format!("migrated config to version {}", CONFIG_VERSION).as_str(), Some("config"));
+        }
+
+        config.warn_unknown_settings();
+        Ok(config)
+    }
+
+    /// Warn once per load about settings with values this version doesn't
+    /// know, so the getters reading them can stay free of side effects.
+    fn warn_unknown_settings(&self) {
+        if let Some(unknown) = self.storage_policy.as_deref()
+            .filter(|policy| !["prefer-keyring", "keyring-only", "file-only"].contains(policy)) {
+            eprintln!("Unknown storage_policy `{}`, treating it as keyring-only", unknown);
+        }
+        for name in self.storage_backends.iter().flatten() {
+            if !storage::BACKENDS.contains(&name.as_str()) {
+                eprintln!("Ignoring unknown storage backend `{}`", name);
+            }
+        }
+        if let Some(unknown) = self.access_token_cache.as_deref().filter(|cache| !["none", "file"].contains(cache)) {
+            eprintln!("Ignoring unknown access_token_cache `{}`", unknown);
+        }
+    }
+
+    /// Upgrade this configuration one schema version at a time.
+    fn migrate(&mut self) {
+        while self.version < CONFIG_VERSION {
+            if self.version == 0 {
+                self.migrate_credentials();
+            }
+            self.version += 1;
+        }
+    }
+
+    /// Find the `AppConfig` for a request, preferring the most specific match:
+    /// `owner/repo`, then an `owner/repo` pattern, then `owner`, then an owner
+    /// pattern, then the request host, then `default`.
     pub fn config_for(&self, request: &CredentialRequest) -> Option<AppConfig> {
         if self.app_configs.is_none() {
             return None
         }
         let configs = self.app_configs.to_owned().unwrap();
 
-        let criterion = if request.host == String::from("gist.github.com") {
-            String::from("gist")
+        let path = request.path.trim_start_matches('/');
+        let path = path.strip_suffix(".git").unwrap_or(path);
+        let mut path_parts = path.split('/');
+        let owner = if request.host.starts_with("gist.") {
+            "gist"
         } else {
-            let path = request.path.clone();
-            let path_parts: Vec<&str> = path.split("/").collect();
-            String::from(path_parts[0])
+            path_parts.next().unwrap_or("")
         };
+        let repo = path_parts.next().unwrap_or("");
 
-        // TODO: also compare owner/repo maybe, and allow that to
-        // override plain owner matches
-        configs.into_iter().find(|ac| ac.path == criterion)
-    }
+        let mut best: Option<(u8, AppConfig)> = None;
+        for ac in configs {
+            let rank = match ac.match_rank(owner, repo, &request.host) {
+                Some(rank) => rank,
+                None => continue,
+            };
+            util::trace("config", format!("candidate path={} rank={}", ac.path, rank).as_str(), Some("config"));
 
-    pub fn credential_for(&self, client_id: String) -> Option<Credential> {
-        if self.credentials.is_none() {
-            return None
+            match &best {
+                Some((best_rank, _)) if *best_rank >= rank => {},
+                _ => best = Some((rank, ac)),
+            }
         }
-        let credentials = self.credentials.to_owned().unwrap();
 
-        // TODO: also compare owner/repo maybe, and allow that to
-        // override plain owner matches
-        credentials.into_iter().find(|sc| sc.client_id == client_id)
+        if let Some((rank, ac)) = &best {
+            util::trace("config", format!("{} matched app config path={} rank={}", request, ac.path, rank).as_str(), Some("config"));
+        }
+        best.map(|(_, ac)| ac)
+    }
+
+    /// The stored credential for an account. Without one, the last entry
+    /// for the App is used, like the keychain's most recently logged in
+    /// account.
+    pub fn credential_for(&self, host: &str, client_id: &str, login: &str) -> Option<Credential> {
+        let credentials = self.credentials.to_owned()?;
+
+        credentials.into_iter().rev().find(|sc| sc.belongs_to(host, client_id, login))
+    }
+
+    /// Store a credential in the configuration file, merging it into the
+    /// file's current contents under a lock. With `as_default` it becomes
+    /// the one used for requests that don't name an account.
+    pub fn store_credential(&mut self, credential: &Credential, as_default: bool) -> Result<(), Box<dyn Error>> {
+        *self = update_config(|config| config.insert_credential(credential, as_default))?;
+        Ok(())
+    }
+
+    /// Remove a request's credential from the configuration file, merging
+    /// the removal into the file's current contents under a lock.
+    pub fn delete_credential(&mut self, request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
+        let client_id = request.client_id();
+        *self = update_config(|config| config.remove_credential(&request.host, &client_id, &request.login))?;
+        Ok(())
     }
 
-    pub fn store_credential(&mut self, credential: &Credential) -> Result<(), confy::ConfyError> {
+    fn insert_credential(&mut self, credential: &Credential, as_default: bool) {
         match self.credentials.to_owned() {
             Some(mut creds) => {
-                match creds.iter().position(|sc| sc.client_id == credential.client_id) {
+                let existing = creds.iter().position(|sc| {
+                    sc.host == credential.host && sc.client_id == credential.client_id
+                        && (sc.login == credential.login || sc.login.is_empty())
+                });
+                // The last entry is the default, so other accounts keep
+                // their place or go first.
+                match existing {
+                    Some(index) if !as_default => creds[index] = credential.clone(),
                     Some(index) => {
                         creds.remove(index);
+                        creds.push(credential.clone());
                     },
-                    None => {},
-                };
-                creds.push(credential.clone());
+                    None if !as_default => creds.insert(0, credential.clone()),
+                    None => creds.push(credential.clone()),
+                }
                 self.credentials = Some(creds)
             },
             None => self.credentials = Some(vec![credential.clone()])
         }
+    }
+
+    fn remove_credential(&mut self, host: &str, client_id: &str, login: &str) {
+        let mut creds = match self.credentials.to_owned() {
+            Some(creds) => creds,
+            None => return,
+        };
+
+        if let Some(index) = creds.iter().rposition(|sc| sc.belongs_to(host, client_id, login)) {
+            creds.remove(index);
+        }
+
+        if creds.is_empty() {
+            self.credentials = None
+        } else {
+            self.credentials = Some(creds)
+        }
+    }
+
+    /// Every `(host, client_id, login)` a credential may be stored under:
+    /// those in this file plus one for each `AppConfig`, and for gists when
+    /// it is for github.com. Keyring and pass entries for accounts not named
+    /// here can't be listed.
+    pub fn credential_identities(&self) -> Vec<(String, String, String)> {
+        let mut identities: Vec<(String, String, String)> = Vec::new();
+
+        for sc in self.credentials.to_owned().unwrap_or_default() {
+            identities.push((sc.host, sc.client_id, sc.login));
+        }
+        for ac in self.app_configs.to_owned().unwrap_or_default() {
+            let login = ac.login.unwrap_or_default();
+            match ac.host {
+                Some(host) => identities.push((host, ac.client_id, login)),
+                None => {
+                    identities.push((String::from("github.com"), ac.client_id.clone(), login.clone()));
+                    identities.push((String::from("gist.github.com"), ac.client_id, login));
+                }
+            }
+        }
 
-        if cfg!(unix) {
-            let path = confy::get_configuration_file_path("github-keychain", None)?;
-            match fs::metadata(&path) {
-                Ok(meta) => {
-                    let mut perms = meta.permissions();
-                    let mode = 0o600;
-                    if perms.mode() != mode {
-                        perms.set_mode(mode);
-                        fs::set_permissions(path, perms).unwrap();
-                    }
-                },
-                Err(_) => {}
+        let mut unique = Vec::new();
+        for identity in identities {
+            if !unique.contains(&identity) {
+                unique.push(identity);
             }
         }
-        confy::store("github-keychain", None, self)
+        unique
     }
 
-    pub fn delete_credential(&mut self, request: &CredentialRequest) -> Result<(), confy::ConfyError> {
-        if self.credentials.is_none() {
-            return Ok(())
+    /// Assign a host to credentials stored before credentials were keyed by
+    /// host, using the host of their `AppConfig` or github.com. Returns
+    /// whether anything changed.
+    fn migrate_credentials(&mut self) -> bool {
+        let mut creds = match self.credentials.to_owned() {
+            Some(creds) => creds,
+            None => return false,
+        };
+
+        let mut migrated = false;
+        for credential in creds.iter_mut().filter(|sc| sc.host.is_empty()) {
+            credential.host = self.config_for_client_id(&credential.client_id)
+                .and_then(|ac| ac.host)
+                .unwrap_or_else(|| String::from("github.com"));
+            migrated = true;
         }
-        let client_id = request.client_id();
 
-        let mut creds = self.credentials.to_owned().unwrap();
-        match creds.iter().position(|sc| sc.client_id == client_id) {
-            Some(index) => {
-                creds.remove(index);
-            },
-            None => {},
+        self.credentials = Some(creds);
+        migrated
+    }
+
+    /// The storage backends to use, in order, defaulting to the OS keyring
+    /// followed by this configuration file, as restricted by `storage_policy`:
+    /// `keyring-only` never writes credentials to this file in plaintext and
+    /// `file-only` never touches the keyring.
+    pub fn storage_backends(&self) -> Vec<String> {
+        let backends = match &self.storage_backends {
+            Some(backends) => backends.clone(),
+            None => vec![String::from("keyring"), String::from("file")],
         };
 
-        if creds.is_empty() {
-            self.credentials = None
-        } else {
-            self.credentials = Some(creds)
+        match self.storage_policy.as_deref() {
+            None | Some("prefer-keyring") => backends,
+            Some("file-only") => vec![String::from("file")],
+            Some("keyring-only") => backends.into_iter().filter(|b| b != "file").collect(),
+            // Unknown policies are reported by `warn_unknown_settings`.
+            Some(_) => backends.into_iter().filter(|b| b != "file").collect(),
         }
+    }
 
-        confy::store("github-keychain", None, self)
+    pub fn encryption_key_file(&self) -> Option<String> {
+        self.encryption_key_file.clone()
+    }
+
+    /// The `pass` compatible program used by the `pass` backend.
+    pub fn pass_command(&self) -> String {
+        self.pass_command.clone().unwrap_or_else(|| String::from("pass"))
+    }
+
+    /// The directory in the password store holding `pass` backend entries.
+    pub fn pass_prefix(&self) -> String {
+        self.pass_prefix.clone().unwrap_or_else(|| String::from("github-keychain"))
+    }
+
+    /// Whether access tokens are cached in a file of their own, leaving only
+    /// refresh tokens in the storage backends.
+    pub fn caches_access_tokens(&self) -> bool {
+        match self.access_token_cache.as_deref() {
+            None | Some("none") => false,
+            Some("file") => true,
+            Some(_) => false,
+        }
+    }
+
+    /// How long before its expiry an access token is refreshed, so it doesn't
+    /// expire part way through a long fetch or push. Defaults to 5 minutes.
+    pub fn refresh_margin(&self) -> Duration {
+        Duration::seconds(self.refresh_margin_seconds.unwrap_or(300).max(0))
+    }
+
+    /// How long before a refresh token expires `get` starts warning about it.
+    /// Defaults to 14 days.
+    pub fn refresh_token_warning(&self) -> Duration {
+        Duration::days(self.refresh_token_warning_days.unwrap_or(14).max(0))
+    }
+
+    /// Whether `get` must never start a device flow, set with
+    /// `non_interactive = true` or `GIT_KEYCHAIN_NON_INTERACTIVE=1`.
+    pub fn non_interactive(&self) -> bool {
+        if let Ok(val) = std::env::var("GIT_KEYCHAIN_NON_INTERACTIVE") {
+            return val == "1" || val == "true"
+        }
+        self.non_interactive.unwrap_or(false)
+    }
+
+    pub fn device_flow(&self) -> DeviceFlowConfig {
+        self.device_flow.clone().unwrap_or_default()
+    }
+
+    fn override_device_flow(&mut self, overrides: &DeviceFlowConfig) {
+        self.device_flow = Some(self.device_flow().merge(overrides));
+    }
+
+    /// The fallback helpers to consult, in order: `fallback` followed by `fallbacks`.
+    pub fn fallback_helpers(&self) -> Vec<String> {
+        let mut helpers = vec![self.fallback.clone()];
+        if let Some(fallbacks) = &self.fallbacks {
+            helpers.extend(fallbacks.iter().cloned());
+        }
+        helpers.retain(|helper| !helper.trim().is_empty());
+        helpers
+    }
+
+    pub fn config_for_client_id(&self, client_id: &str) -> Option<AppConfig> {
+        let configs = self.app_configs.to_owned()?;
+
+        configs.into_iter().find(|ac| ac.client_id == client_id)
     }
 
     pub fn default_config(&self) -> Option<AppConfig> {
@@ -211,9 +690,113 @@ impl GithubKeychainConfig {
     }
 }
 
+/// Apply `change` to the configuration file while holding an exclusive lock
+/// on it, so concurrent helpers don't lose each other's updates, returning
+/// the updated configuration. The file is replaced atomically by a temporary
+/// file that is only ever readable by the current user.
+fn update_config<F: FnOnce(&mut GithubKeychainConfig)>(change: F) -> Result<GithubKeychainConfig, Box<dyn Error>> {
+    let path = confy::get_configuration_file_path("github-keychain", None)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let lock = storage::exclusive_lock("config.lock")?;
+
+    let mut config = match fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str(&contents)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => GithubKeychainConfig::default(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    change(&mut config);
+
+    let contents = toml::to_string(&config)?;
+    storage::write_private_file(&path, contents.as_bytes())?;
+
+    drop(lock);
+    Ok(config)
+}
+
+fn check_version(version: i64) -> Result<(), CredentialError> {
+    if version > CONFIG_VERSION as i64 {
+        return Err(CredentialError(format!(
+            "config file version {} is newer than the supported version {}, please upgrade git-credential-github-keychain",
+            version, CONFIG_VERSION
+        )))
+    }
+    Ok(())
+}
+
 /// `GithubKeychainConfig` implements `Default`
 impl ::std::default::Default for GithubKeychainConfig {
-    fn default() -> Self { Self { version: 0, app_configs: None, credentials: None, fallback: String::new() } }
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            app_configs: None,
+            credentials: None,
+            fallback: String::new(),
+            fallbacks: None,
+            storage_backends: None,
+            storage_policy: None,
+            encryption_key_file: None,
+            pass_command: None,
+            pass_prefix: None,
+            access_token_cache: None,
+            refresh_margin_seconds: None,
+            refresh_token_warning_days: None,
+            non_interactive: None,
+            device_flow: None,
+        }
+    }
+}
+
+fn expires_within(expiry: DateTime<Utc>, margin: Duration) -> bool {
+    let now = Utc::now();
+    now + margin > expiry
+}
+
+/// Parse an RFC 3339 expiry as issued by GitHub and stored in the config.
+/// An empty value means no expiry, anything unparseable is taken as already
+/// expired so a corrupted entry is refreshed rather than served forever.
+pub fn parse_expiry(expiry: &str) -> Option<DateTime<Utc>> {
+    if expiry.is_empty() {
+        return None;
+    }
+    match DateTime::parse_from_rfc3339(expiry) {
+        Ok(time) => Some(time.with_timezone(&Utc)),
+        Err(e) => {
+            util::trace("config", format!("unparseable expiry {:?}, treating it as expired: {}", expiry, e).as_str(), Some("config"));
+            Some(DateTime::UNIX_EPOCH)
+        }
+    }
+}
+
+/// Stores expiries as RFC 3339 strings, an empty string when there is none,
+/// which keeps the format readable by earlier versions.
+mod expiry_format {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(expiry: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match expiry {
+            Some(expiry) => serializer.serialize_str(&expiry.to_rfc3339()),
+            None => serializer.serialize_str(""),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+        let expiry = String::deserialize(deserializer)?;
+        Ok(super::parse_expiry(&expiry))
+    }
+}
+
+fn is_github_host(host: &str) -> bool {
+    host == "github.com" || host == "gist.github.com"
+}
+
+/// The host serving the OAuth endpoints for a git host, `gist.` hosts
+/// authenticate against their parent host.
+fn auth_host(host: &str) -> &str {
+    host.strip_prefix("gist.").unwrap_or(host)
 }
 
 #[derive(Debug)]
@@ -231,7 +814,7 @@ impl fmt::Display for ParseError {
 
 #[cfg(test)]
 mod tests {
-    use crate::{Credential};
+    use crate::{check_version, parse_expiry, AppConfig, Credential, CredentialRequest, DeviceFlowConfig, GithubKeychainConfig, CONFIG_VERSION};
     use chrono::offset::Utc;
     use chrono::{DateTime, Duration};
 
@@ -240,13 +823,15 @@ mod tests {
         let expires_in = Duration::seconds(28800);
         let mut expiry: DateTime<Utc> = Utc::now();
         expiry = expiry + expires_in;
-        let calculated_expiry = expiry.to_rfc3339();
 
         let credential = Credential {
             client_id: String::from("irrelevant"),
             token: String::from("irrelevant"),
-            expiry: calculated_expiry,
+            expiry: Some(expiry),
             refresh_token: String::from("irrelevant"),
+            host: String::from("irrelevant"),
+            login: String::from("irrelevant"),
+            refresh_token_expiry: None,
         };
 
         assert_eq!(false, credential.is_expired());
@@ -257,13 +842,15 @@ mod tests {
         let expires_in = Duration::seconds(42);
         let mut expiry: DateTime<Utc> = Utc::now();
         expiry = expiry - expires_in;
-        let calculated_expiry = expiry.to_rfc3339();
 
         let credential = Credential {
             client_id: String::from("irrelevant"),
             token: String::from("irrelevant"),
-            expiry: calculated_expiry,
+            expiry: Some(expiry),
             refresh_token: String::from("irrelevant"),
+            host: String::from("irrelevant"),
+            login: String::from("irrelevant"),
+            refresh_token_expiry: None,
         };
 
         assert_eq!(true, credential.is_expired());
@@ -280,4 +867,498 @@ mod tests {
             panic!("failed to parse value!");
         }
     }
+
+    #[test]
+    fn credential_update_from_request_applies_password_expiry_and_refresh_token() {
+        let mut credential = Credential {
+            client_id: String::from("irrelevant"),
+            token: String::from("old-token"),
+            expiry: parse_expiry("2023-12-02T20:09:59.620424+00:00"),
+            refresh_token: String::from("old-refresh"),
+            host: String::from("irrelevant"),
+            login: String::from("irrelevant"),
+            refresh_token_expiry: parse_expiry("2023-12-02T20:09:59.620424+00:00"),
+        };
+        let mut request = CredentialRequest::empty();
+        request.password = String::from("new-token");
+        request.password_expiry_utc = String::from("1701576599");
+        request.oauth_refresh_token = String::from("new-refresh");
+
+        credential.update_from_request(&request);
+
+        assert_eq!("new-token", credential.token);
+        assert_eq!(parse_expiry("2023-12-03T04:09:59+00:00"), credential.expiry);
+        assert_eq!("new-refresh", credential.refresh_token);
+        assert!(!credential.refresh_token_expires_within(Duration::days(14)));
+    }
+
+    #[test]
+    fn credential_update_from_request_keeps_refresh_token_when_not_sent() {
+        let mut credential = Credential {
+            client_id: String::from("irrelevant"),
+            token: String::from("old-token"),
+            expiry: parse_expiry("2023-12-03T04:09:59+00:00"),
+            refresh_token: String::from("old-refresh"),
+            host: String::from("irrelevant"),
+            login: String::from("irrelevant"),
+            refresh_token_expiry: None,
+        };
+        let mut request = CredentialRequest::empty();
+        request.password = String::from("new-token");
+
+        credential.update_from_request(&request);
+
+        assert_eq!("new-token", credential.token);
+        assert_eq!(parse_expiry("2023-12-03T04:09:59+00:00"), credential.expiry);
+        assert_eq!("old-refresh", credential.refresh_token);
+    }
+
+    #[test]
+    fn credential_request_attributes_skips_empty_values() {
+        let mut request = CredentialRequest::empty();
+        request.protocol = String::from("https");
+        request.host = String::from("example.com");
+        request.path = String::from("owner/repo.git");
+        request.port = String::from("8443");
+        request.username = String::from("someone");
+
+        assert_eq!(
+            "protocol=https\nhost=example.com\npath=owner/repo.git\nport=8443\nusername=someone\n",
+            request.attributes()
+        );
+    }
+
+    #[test]
+    fn config_fallback_helpers_chains_fallback_and_fallbacks_in_order() {
+        let config = GithubKeychainConfig {
+            fallback: String::from("cache --timeout 600"),
+            fallbacks: Some(vec![String::from("libsecret"), String::from(""), String::from("/usr/local/bin/vault-helper")]),
+            ..GithubKeychainConfig::default()
+        };
+
+        assert_eq!(
+            vec!["cache --timeout 600", "libsecret", "/usr/local/bin/vault-helper"],
+            config.fallback_helpers()
+        );
+    }
+
+    #[test]
+    fn config_fallback_helpers_is_empty_without_fallbacks() {
+        assert!(GithubKeychainConfig::default().fallback_helpers().is_empty());
+    }
+
+    fn app_config(path: &str, client_id: &str) -> AppConfig {
+        AppConfig { path: String::from(path), client_id: String::from(client_id), host: None, login: None }
+    }
+
+    fn host_app_config(host: &str, path: &str, client_id: &str) -> AppConfig {
+        AppConfig { host: Some(String::from(host)), ..app_config(path, client_id) }
+    }
+
+    fn request_for(host: &str, path: &str, app_configs: Vec<AppConfig>) -> CredentialRequest {
+        let mut request = CredentialRequest::empty();
+        request.host = String::from(host);
+        request.path = String::from(path);
+        request.config = GithubKeychainConfig {
+            app_configs: Some(app_configs),
+            ..GithubKeychainConfig::default()
+        };
+        request
+    }
+
+    #[test]
+    fn config_for_prefers_repo_over_owner_regardless_of_order() {
+        let configs = vec![
+            app_config("acme", "Iv1.owner"),
+            app_config("acme/secret", "Iv1.repo"),
+        ];
+
+        let request = request_for("github.com", "acme/secret.git", configs.clone());
+        assert_eq!("Iv1.repo", request.app_config().unwrap().client_id);
+
+        let request = request_for("github.com", "acme/other.git", configs);
+        assert_eq!("Iv1.owner", request.app_config().unwrap().client_id);
+    }
+
+    #[test]
+    fn config_for_falls_back_to_host_then_default() {
+        let configs = vec![
+            app_config("default", "Iv1.default"),
+            app_config("github.com", "Iv1.host"),
+        ];
+
+        let request = request_for("github.com", "someone/repo", configs.clone());
+        assert_eq!("Iv1.host", request.app_config().unwrap().client_id);
+
+        let request = request_for("gist.github.com", "abc123", configs.clone());
+        assert_eq!("Iv1.default", request.app_config().unwrap().client_id);
+
+        let request = request_for("gitlab.com", "someone/repo", configs);
+        assert!(request.app_config().is_none());
+    }
+
+    #[test]
+    fn config_for_matches_gist_host() {
+        let configs = vec![app_config("gist", "Iv1.gist")];
+
+        let request = request_for("gist.github.com", "abc123", configs);
+        assert_eq!("Iv1.gist", request.app_config().unwrap().client_id);
+    }
+
+    #[test]
+    fn config_for_matches_glob_paths_below_literal_paths() {
+        let configs = vec![
+            app_config("acme-*", "Iv1.glob"),
+            app_config("*/infra-*", "Iv1.infra"),
+            app_config("acme-tools", "Iv1.literal"),
+        ];
+
+        let request = request_for("github.com", "acme-web/site.git", configs.clone());
+        assert_eq!("Iv1.glob", request.app_config().unwrap().client_id);
+
+        let request = request_for("github.com", "acme-web/infra-prod.git", configs.clone());
+        assert_eq!("Iv1.infra", request.app_config().unwrap().client_id);
+
+        let request = request_for("github.com", "acme-tools/site.git", configs.clone());
+        assert_eq!("Iv1.literal", request.app_config().unwrap().client_id);
+
+        let request = request_for("github.com", "other/site.git", configs);
+        assert!(request.app_config().is_none());
+    }
+
+    #[test]
+    fn config_for_matches_anchored_regex_paths() {
+        let configs = vec![app_config("^(acme|widgets)-[0-9]+", "Iv1.regex")];
+
+        let request = request_for("github.com", "widgets-42/repo", configs.clone());
+        assert_eq!("Iv1.regex", request.app_config().unwrap().client_id);
+
+        let request = request_for("github.com", "widgets-42x/repo", configs.clone());
+        assert!(request.app_config().is_none());
+
+        let request = request_for("github.com", "xacme-1/repo", configs);
+        assert!(request.app_config().is_none());
+    }
+
+    #[test]
+    fn config_for_prefers_host_specific_configs() {
+        let configs = vec![
+            app_config("acme", "Iv1.dotcom"),
+            host_app_config("ghes.example.com", "acme", "Iv1.ghes"),
+            host_app_config("acme.ghe.com", "default", "Iv1.ghe"),
+        ];
+
+        let request = request_for("github.com", "acme/repo", configs.clone());
+        assert_eq!("Iv1.dotcom", request.app_config().unwrap().client_id);
+        assert_eq!("github.com", request.auth_host());
+
+        let request = request_for("ghes.example.com", "acme/repo", configs.clone());
+        assert_eq!("Iv1.ghes", request.app_config().unwrap().client_id);
+        assert_eq!("ghes.example.com", request.auth_host());
+
+        let request = request_for("acme.ghe.com", "anyone/repo", configs);
+        assert_eq!("Iv1.ghe", request.app_config().unwrap().client_id);
+    }
+
+    #[test]
+    fn auth_host_uses_parent_host_for_gists() {
+        let configs = vec![app_config("gist", "Iv1.gist")];
+
+        let request = request_for("gist.github.com", "abc123", configs);
+        assert_eq!("github.com", request.auth_host());
+    }
+
+    fn stored_credential(host: &str, client_id: &str, token: &str) -> Credential {
+        Credential {
+            client_id: String::from(client_id),
+            token: String::from(token),
+            host: String::from(host),
+            ..Credential::default()
+        }
+    }
+
+    #[test]
+    fn credential_for_distinguishes_hosts_with_the_same_client_id() {
+        let config = GithubKeychainConfig {
+            credentials: Some(vec![
+                stored_credential("github.com", "Iv1.shared", "dotcom-token"),
+                stored_credential("ghes.example.com", "Iv1.shared", "ghes-token"),
+            ]),
+            ..GithubKeychainConfig::default()
+        };
+
+        assert_eq!("dotcom-token", config.credential_for("github.com", "Iv1.shared", "").unwrap().token);
+        assert_eq!("ghes-token", config.credential_for("ghes.example.com", "Iv1.shared", "").unwrap().token);
+        assert!(config.credential_for("other.example.com", "Iv1.shared", "").is_none());
+    }
+
+    #[test]
+    fn migrate_credentials_assigns_hosts_to_legacy_credentials() {
+        let mut config = GithubKeychainConfig {
+            app_configs: Some(vec![host_app_config("ghes.example.com", "acme", "Iv1.ghes")]),
+            credentials: Some(vec![
+                stored_credential("", "Iv1.dotcom", "dotcom-token"),
+                stored_credential("", "Iv1.ghes", "ghes-token"),
+            ]),
+            ..GithubKeychainConfig::default()
+        };
+
+        assert!(config.migrate_credentials());
+        assert_eq!("dotcom-token", config.credential_for("github.com", "Iv1.dotcom", "").unwrap().token);
+        assert_eq!("ghes-token", config.credential_for("ghes.example.com", "Iv1.ghes", "").unwrap().token);
+        assert!(!config.migrate_credentials());
+    }
+
+    #[test]
+    fn credential_without_host_or_login_parses_from_json() {
+        let val = "{\"client_id\":\"irrelevant\",\"token\":\"irrelevant\",\"expiry\":\"\",\"refresh_token\":\"irrelevant\"}";
+        let credential = serde_json::from_str::<Credential>(val).unwrap();
+
+        assert!(credential.host.is_empty());
+        assert!(credential.login.is_empty());
+    }
+
+    #[test]
+    fn credential_for_selects_the_requested_account() {
+        let mut personal = stored_credential("github.com", "Iv1.shared", "personal-token");
+        personal.login = String::from("octocat");
+        let mut work = stored_credential("github.com", "Iv1.shared", "work-token");
+        work.login = String::from("octocat-work");
+        let config = GithubKeychainConfig {
+            credentials: Some(vec![personal, work]),
+            ..GithubKeychainConfig::default()
+        };
+
+        assert_eq!("work-token", config.credential_for("github.com", "Iv1.shared", "octocat-work").unwrap().token);
+        assert_eq!("personal-token", config.credential_for("github.com", "Iv1.shared", "octocat").unwrap().token);
+        assert_eq!("work-token", config.credential_for("github.com", "Iv1.shared", "").unwrap().token);
+    }
+
+    #[test]
+    fn insert_credential_keeps_the_default_account_unless_asked() {
+        let mut personal = stored_credential("github.com", "Iv1.shared", "personal-token");
+        personal.login = String::from("octocat");
+        let mut work = stored_credential("github.com", "Iv1.shared", "work-token");
+        work.login = String::from("octocat-work");
+        let mut config = GithubKeychainConfig::default();
+
+        config.insert_credential(&personal, true);
+        config.insert_credential(&work, false);
+        assert_eq!("personal-token", config.credential_for("github.com", "Iv1.shared", "").unwrap().token);
+
+        work.token = String::from("refreshed-work-token");
+        config.insert_credential(&work, true);
+        assert_eq!("refreshed-work-token", config.credential_for("github.com", "Iv1.shared", "").unwrap().token);
+        assert_eq!(2, config.credentials.unwrap().len());
+    }
+
+    #[test]
+    fn resolve_login_prefers_git_username_over_app_config() {
+        let configs = vec![AppConfig { login: Some(String::from("octocat-work")), ..app_config("acme", "Iv1.shared") }];
+
+        let mut request = request_for("github.com", "acme/repo", configs.clone());
+        request.resolve_login();
+        assert_eq!("octocat-work", request.login);
+
+        let mut request = request_for("github.com", "acme/repo", configs.clone());
+        request.username = String::from("octocat");
+        request.resolve_login();
+        assert_eq!("octocat", request.login);
+        assert!(request.username.is_empty());
+
+        let mut request = request_for("github.com", "acme/repo", configs);
+        request.username = String::from("Iv1.shared");
+        request.resolve_login();
+        assert_eq!("octocat-work", request.login);
+        assert_eq!("Iv1.shared", request.username);
+    }
+
+    #[test]
+    fn migrate_upgrades_version_zero_configs() {
+        let mut config = GithubKeychainConfig {
+            version: 0,
+            credentials: Some(vec![stored_credential("", "Iv1.dotcom", "dotcom-token")]),
+            ..GithubKeychainConfig::default()
+        };
+
+        config.migrate();
+
+        assert_eq!(CONFIG_VERSION, config.version);
+        assert_eq!("dotcom-token", config.credential_for("github.com", "Iv1.dotcom", "").unwrap().token);
+    }
+
+    #[test]
+    fn check_version_refuses_newer_configs() {
+        assert!(check_version(0).is_ok());
+        assert!(check_version(CONFIG_VERSION as i64).is_ok());
+        assert!(check_version(CONFIG_VERSION as i64 + 1).is_err());
+    }
+
+    #[test]
+    fn credential_identities_combines_credentials_and_app_configs() {
+        let mut work = stored_credential("github.com", "Iv1.shared", "work-token");
+        work.login = String::from("octocat-work");
+        let config = GithubKeychainConfig {
+            app_configs: Some(vec![
+                app_config("octocat", "Iv1.shared"),
+                host_app_config("ghes.example.com", "acme", "Iv1.ghes"),
+            ]),
+            credentials: Some(vec![work, stored_credential("github.com", "Iv1.shared", "token")]),
+            ..GithubKeychainConfig::default()
+        };
+
+        let identities = config.credential_identities();
+
+        assert_eq!(vec![
+            (String::from("github.com"), String::from("Iv1.shared"), String::from("octocat-work")),
+            (String::from("github.com"), String::from("Iv1.shared"), String::new()),
+            (String::from("gist.github.com"), String::from("Iv1.shared"), String::new()),
+            (String::from("ghes.example.com"), String::from("Iv1.ghes"), String::new()),
+        ], identities);
+    }
+
+    #[test]
+    fn storage_backends_follow_storage_policy() {
+        let mut config = GithubKeychainConfig::default();
+        assert_eq!(vec!["keyring", "file"], config.storage_backends());
+
+        config.storage_policy = Some(String::from("keyring-only"));
+        assert_eq!(vec!["keyring"], config.storage_backends());
+
+        config.storage_backends = Some(vec![String::from("keyring"), String::from("file"), String::from("pass")]);
+        assert_eq!(vec!["keyring", "pass"], config.storage_backends());
+
+        config.storage_policy = Some(String::from("file-only"));
+        assert_eq!(vec!["file"], config.storage_backends());
+
+        config.storage_policy = Some(String::from("keyring_only"));
+        assert_eq!(vec!["keyring", "pass"], config.storage_backends());
+    }
+
+    #[test]
+    fn config_round_trips_through_toml() {
+        let mut credential = stored_credential("github.com", "Iv1.shared", "token");
+        credential.login = String::from("octocat");
+        let config = GithubKeychainConfig {
+            fallback: String::from("osxkeychain"),
+            app_configs: Some(vec![host_app_config("ghes.example.com", "acme", "Iv1.ghes")]),
+            credentials: Some(vec![credential]),
+            device_flow: Some(DeviceFlowConfig { timeout_seconds: Some(60), ..DeviceFlowConfig::default() }),
+            ..GithubKeychainConfig::default()
+        };
+
+        let parsed: GithubKeychainConfig = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+
+        assert_eq!(Some(60), parsed.device_flow().timeout_seconds);
+
+        assert_eq!("osxkeychain", parsed.fallback);
+        assert_eq!(Some(String::from("ghes.example.com")), parsed.config_for_client_id("Iv1.ghes").unwrap().host);
+        assert_eq!("token", parsed.credential_for("github.com", "Iv1.shared", "octocat").unwrap().token);
+    }
+
+    #[test]
+    fn device_flow_overrides_take_precedence() {
+        let mut request = CredentialRequest::empty();
+        request.config.device_flow = Some(DeviceFlowConfig {
+            timeout_seconds: Some(60),
+            interval_seconds: Some(10),
+            open_browser: Some(true),
+            ..DeviceFlowConfig::default()
+        });
+        assert_eq!(std::time::Duration::from_secs(900), CredentialRequest::empty().config.device_flow().timeout());
+
+        request.override_device_flow(&DeviceFlowConfig { timeout_seconds: Some(120), max_attempts: Some(3), ..DeviceFlowConfig::default() });
+
+        let device_flow = request.config.device_flow();
+        assert_eq!(std::time::Duration::from_secs(120), device_flow.timeout());
+        assert_eq!(std::time::Duration::from_secs(10), device_flow.interval());
+        assert_eq!(Some(3), device_flow.max_attempts);
+        assert_eq!(Some(true), device_flow.open_browser);
+    }
+
+    #[test]
+    fn credential_expires_within_margin() {
+        let credential = Credential {
+            expiry: Some(Utc::now() + Duration::seconds(120)),
+            ..Credential::default()
+        };
+
+        assert!(!credential.is_expired());
+        assert!(!credential.expires_within(Duration::seconds(60)));
+        assert!(credential.expires_within(Duration::seconds(300)));
+    }
+
+    #[test]
+    fn credential_update_from_request_keeps_expiry_echoed_by_git() {
+        let mut credential = Credential {
+            expiry: parse_expiry("2023-12-03T04:09:59.620424+00:00"),
+            ..Credential::default()
+        };
+        let mut request = CredentialRequest::empty();
+        request.password_expiry_utc = credential.expiry_timestamp().unwrap().to_string();
+
+        credential.update_from_request(&request);
+
+        assert_eq!(parse_expiry("2023-12-03T04:09:59.620424+00:00"), credential.expiry);
+    }
+
+    #[test]
+    fn credential_refresh_token_expiry_is_tracked_when_issued() {
+        let mut credential = Credential {
+            refresh_token: String::from("ghr_abc"),
+            ..Credential::default()
+        };
+        assert!(!credential.refresh_token_expires_within(Duration::days(365)));
+
+        credential.set_refresh_token_issued_now(None);
+
+        assert!(!credential.refresh_token_expires_within(Duration::days(14)));
+        assert!(credential.refresh_token_expires_within(Duration::days(200)));
+
+        credential.set_refresh_token_issued_now(Some(86_400 * 30));
+
+        assert!(credential.refresh_token_expires_within(Duration::days(31)));
+    }
+
+    #[test]
+    fn credential_without_refresh_token_has_no_refresh_token_expiry() {
+        let mut credential = Credential::default();
+
+        credential.set_refresh_token_issued_now(None);
+
+        assert!(credential.refresh_token_expiry.is_none());
+    }
+
+    #[test]
+    fn credential_with_unparseable_expiry_is_expired() {
+        let val = "{\"client_id\":\"irrelevant\",\"token\":\"irrelevant\",\"expiry\":\"not a date\",\"refresh_token\":\"\"}";
+        let credential = serde_json::from_str::<Credential>(val).unwrap();
+
+        assert!(credential.is_expired());
+    }
+
+    #[test]
+    fn credential_with_missing_expiry_is_expired_only_when_refreshable() {
+        let mut credential = Credential {
+            token: String::from("ghu_abc"),
+            ..Credential::default()
+        };
+        assert!(!credential.is_expired());
+
+        credential.refresh_token = String::from("ghr_abc");
+        assert!(credential.is_expired());
+    }
+
+    #[test]
+    fn credential_expiry_serializes_as_rfc3339() {
+        let credential = Credential {
+            expiry: parse_expiry("2023-12-03T04:09:59+00:00"),
+            ..Credential::default()
+        };
+        let serialized = serde_json::to_string(&credential).unwrap();
+
+        assert!(serialized.contains("\"expiry\":\"2023-12-03T04:09:59+00:00\""));
+        assert!(serialized.contains("\"refresh_token_expiry\":\"\""));
+        assert_eq!(credential, serde_json::from_str::<Credential>(&serialized).unwrap());
+    }
 }
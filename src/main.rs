@@ -4,7 +4,7 @@ extern crate git_credential_github_keychain;
 
 use std::{result::Result, error::Error, env, process};
 
-use git_credential_github_keychain::{util, storage, CredentialError};
+use git_credential_github_keychain::{util, storage, CredentialError, DeviceFlowConfig};
 
 fn get_password() -> Result<(), Box<dyn Error>> {
     util::trace("main", "processing get_password", Some("main"));
@@ -20,8 +20,15 @@ fn get_password() -> Result<(), Box<dyn Error>> {
             Some(credential) => {
                 util::trace("main", "Credential resolved, printing to git", Some("main"));
 
-                println!("username=x-oauth-token");
+                if request.login.is_empty() {
+                    println!("username=x-oauth-token");
+                } else {
+                    println!("username={}", request.login);
+                }
                 println!("password={}", credential.token);
+                if let Some(expiry) = credential.expiry_timestamp() {
+                    println!("password_expiry_utc={}", expiry);
+                }
                 Ok(())
             },
             None => {
@@ -32,13 +39,22 @@ fn get_password() -> Result<(), Box<dyn Error>> {
         }
     } else {
         util::trace("main", "executing fallback command", Some("main"));
-        util::execute_fallback(request)
+        util::execute_fallback(request, "get")
     }
 }
 
 fn set_password() -> Result<(), Box<dyn Error>> {
     util::trace("main", "processing set_password", Some("main"));
-    Ok(())
+
+    let mut request = util::read_input()?;
+
+    if request.is_configured() {
+        util::trace("main", "request is configured", Some("main"));
+        util::store_request_credential(&mut request)
+    } else {
+        util::trace("main", "executing fallback command", Some("main"));
+        util::execute_fallback(request, "store")
+    }
 }
 
 fn delete_password() -> Result<(), Box<dyn Error>> {
@@ -49,16 +65,45 @@ fn delete_password() -> Result<(), Box<dyn Error>> {
     // We'll have to resolve the app config from the path here
     if request.is_configured() {
         storage::delete_credential(&mut request)?;
+        eprintln!("The password has been deleted");
+        Ok(())
+    } else {
+        util::trace("main", "executing fallback command", Some("main"));
+        util::execute_fallback(request, "erase")
     }
+}
 
-    eprintln!("The password has been deleted");
+const LOGIN_USAGE: &str = "usage: login [client_id] [account] [--timeout SECONDS] [--interval SECONDS] [--max-attempts N] [--qr]";
 
-    Ok(())
-}
+fn login(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut positional = Vec::new();
+    let mut device_flow = DeviceFlowConfig::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue
+        }
+        if arg == "--qr" {
+            device_flow.qr_code = Some(true);
+            continue
+        }
+        let value = args.next().ok_or_else(|| CredentialError(LOGIN_USAGE.into()))?;
+        let invalid = |_| CredentialError(format!("invalid value `{}` for {}", value, arg));
+        match arg.as_str() {
+            "--timeout" => device_flow.timeout_seconds = Some(value.parse().map_err(invalid)?),
+            "--interval" => device_flow.interval_seconds = Some(value.parse().map_err(invalid)?),
+            "--max-attempts" => device_flow.max_attempts = Some(value.parse().map_err(invalid)?),
+            _ => return Err(Box::new(CredentialError(LOGIN_USAGE.into())))
+        }
+    }
 
-fn login(client_id: Option<&String>) -> Result<(), Box<dyn Error>> {
-    let mut conf = util::resolve_username(client_id)?;
+    let mut conf = util::resolve_username(positional.first().copied())?;
     // eprintln!("conf: {:?}", &conf);
+    if let Some(account) = positional.get(1) {
+        conf.login = account.to_string();
+    }
+    conf.override_device_flow(&device_flow);
 
     if conf.username.is_empty() {
         return Err(Box::new(CredentialError("No Client ID configuration found.".into())))
@@ -70,11 +115,18 @@ fn login(client_id: Option<&String>) -> Result<(), Box<dyn Error>> {
     }
 }
 
+fn migrate_storage(args: &[String]) -> Result<(), Box<dyn Error>> {
+    match args {
+        [flag, target] if flag == "--to" => util::migrate_storage(target),
+        _ => Err(Box::new(CredentialError("usage: migrate-storage --to keyring|file|encrypted-file|pass".into())))
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
-        println!("usage: login|get|erase");
+        println!("usage: login|get|erase|migrate-storage");
         return;
     }
 
@@ -85,11 +137,12 @@ fn main() {
     // println!("command is: {}", command);
     let result = match command.as_ref() {
         "store" => set_password(),
-        "login" => login(args.get(2)),
+        "login" => login(&args[2..]),
         "get" => get_password(),
         "erase" => delete_password(),
+        "migrate-storage" => migrate_storage(&args[2..]),
         _ => {
-            println!("usage: login|get|erase");
+            println!("usage: login|get|erase|migrate-storage");
             Ok(())
         },
     };
@@ -1,17 +1,342 @@
 
-use crate::{Credential, CredentialRequest};
-use std::{error::Error};
+use crate::{Credential, CredentialRequest, util};
+use std::{collections::HashMap, env, error::Error, fs, path::{Path, PathBuf}};
+use std::io::Write;
+use std::process::{Command, Stdio};
 use keyring::Keyring;
+use argon2::Argon2;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, KeyInit};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng, rand_core::RngCore};
 
-fn fetch_keychain_credential(request: &CredentialRequest) -> Option<Credential> {
+/// A place credentials can be kept. Stores are consulted in the order listed
+/// in `storage_backends`: `fetch` returns the first credential found,
+/// `store` stops at the first store that succeeds and `delete` removes the
+/// credential from every store holding it. `fetch` returns `Ok(None)` when
+/// the store has no credential and an error when it can't be read.
+pub trait CredentialStore {
+    fn name(&self) -> &str;
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>>;
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>>;
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>>;
+}
+
+/// The OS keychain, e.g. macOS Keychain or the Secret Service on Linux.
+pub struct KeyringStore;
+
+impl CredentialStore for KeyringStore {
+    fn name(&self) -> &str {
+        "keyring"
+    }
+
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+        Ok(fetch_keyed(self, request))
+    }
+
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        store_keyed(self, credential, request)
+    }
+
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        delete_keyed(self, request)
+    }
+}
+
+impl AccountKeyed for KeyringStore {
+    fn read(&self, request: &CredentialRequest, account: &str) -> Option<Credential> {
+        read_keychain_credential(request, account)
+    }
+
+    fn write(&self, request: &CredentialRequest, account: &str, credential: &Credential) -> Result<(), Box<dyn Error>> {
+        let credentials_json = serde_json::to_string(&credential)?;
+        let keyring = Keyring::new(&request.host, account);
+
+        match keyring.set_password(&credentials_json) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    fn remove(&self, request: &CredentialRequest, account: &str) -> Result<(), Box<dyn Error>> {
+        let keyring = Keyring::new(&request.host, account);
+
+        match keyring.delete_password() {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+/// The `credentials` list in the helper's configuration file.
+pub struct FileStore;
+
+impl CredentialStore for FileStore {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+        Ok(fetch_file_credential(request))
+    }
+
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        store_file_credential(credential, request)
+    }
+
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        delete_file_credential(request)
+    }
+}
+
+/// A passphrase or key file encrypted file next to the configuration file,
+/// for machines without an OS keychain. It is unlocked by the key file named
+/// in `encryption_key_file` or the `GIT_KEYCHAIN_PASSPHRASE` environment
+/// variable, so it works without a terminal.
+pub struct EncryptedFileStore;
+
+impl CredentialStore for EncryptedFileStore {
+    fn name(&self) -> &str {
+        "encrypted-file"
+    }
+
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+        // A missing file means nothing is stored yet, while a locked or
+        // undecryptable one must not look empty.
+        let creds = read_encrypted_credentials(request)?;
+        let client_id = request.client_id();
+        Ok(creds.into_iter().find(|sc| sc.belongs_to(&request.host, &client_id, &request.login)))
+    }
+
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        credential.client_id = request.client_id();
+        credential.host = request.host.clone();
+
+        update_encrypted_credentials(request, |creds| {
+            creds.retain(|sc| {
+                !(sc.host == credential.host && sc.client_id == credential.client_id
+                    && (sc.login == credential.login || sc.login.is_empty()))
+            });
+            creds.push(credential.clone());
+        })
+    }
+
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        let client_id = request.client_id();
+
+        update_encrypted_credentials(request, |creds| {
+            creds.retain(|sc| !sc.belongs_to(&request.host, &client_id, &request.login));
+        })
+    }
+}
+
+/// A `pass` (or compatible, e.g. `gopass`) password store, keeping each
+/// credential as JSON in a GPG encrypted entry named
+/// `<pass_prefix>/<host>/<client id>:<login>`.
+pub struct PassStore;
+
+impl CredentialStore for PassStore {
+    fn name(&self) -> &str {
+        "pass"
+    }
+
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+        Ok(fetch_keyed(self, request))
+    }
+
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        store_keyed(self, credential, request)
+    }
+
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        delete_keyed(self, request)
+    }
+}
+
+impl AccountKeyed for PassStore {
+    fn read(&self, request: &CredentialRequest, account: &str) -> Option<Credential> {
+        let entry = pass_entry(request, account);
+        let output = pass_command(request)
+            .args(["show", entry.as_str()])
+            .stderr(Stdio::null())
+            .output();
+
+        match output {
+            Ok(output) if output.status.success() => {
+                match serde_json::from_slice::<Credential>(&output.stdout) {
+                    Ok(stored) => Some(stored),
+                    Err(e) => {
+                        util::trace("store", format!("unable to parse pass entry {}: {}", entry, e).as_str(), Some("store"));
+                        None
+                    }
+                }
+            },
+            _ => None,
+        }
+    }
+
+    fn write(&self, request: &CredentialRequest, account: &str, credential: &Credential) -> Result<(), Box<dyn Error>> {
+        let credentials_json = serde_json::to_string(&credential)?;
+
+        let entry = pass_entry(request, account);
+        let mut child = pass_command(request)
+            .args(["insert", "--multiline", "--force", entry.as_str()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        match child.stdin.take() {
+            Some(mut stdin) => stdin.write_all(credentials_json.as_bytes())?,
+            None => return Err(util::credential_error("Failed to open pass stdin")),
+        }
+
+        check_pass_status(child.wait()?, "insert", &entry)
+    }
+
+    fn remove(&self, request: &CredentialRequest, account: &str) -> Result<(), Box<dyn Error>> {
+        let entry = pass_entry(request, account);
+        let status = pass_command(request)
+            .args(["rm", "--force", entry.as_str()])
+            .stdout(Stdio::null())
+            .status()?;
+
+        check_pass_status(status, "rm", &entry)
+    }
+}
+
+/// The names of the storage backends this crate provides.
+pub const BACKENDS: [&str; 4] = ["keyring", "file", "encrypted-file", "pass"];
+
+/// The built in store called `name`, if there is one.
+pub fn store_named(name: &str) -> Option<Box<dyn CredentialStore>> {
+    match name {
+        "keyring" => Some(Box::new(KeyringStore)),
+        "file" => Some(Box::new(FileStore)),
+        "encrypted-file" => Some(Box::new(EncryptedFileStore)),
+        "pass" => Some(Box::new(PassStore)),
+        _ => None,
+    }
+}
+
+/// Build the stores named by `storage_backends` for a request, in order.
+pub fn configured_stores(request: &CredentialRequest) -> Vec<Box<dyn CredentialStore>> {
+    let mut stores: Vec<Box<dyn CredentialStore>> = Vec::new();
+    for name in request.config.storage_backends() {
+        // Unknown backends are reported when the configuration is loaded.
+        if let Some(store) = store_named(&name) {
+            stores.push(store);
+        }
+    }
+    stores
+}
+
+/// The keyring account for a request: the client ID, qualified with the
+/// account login when one was selected.
+fn keychain_account(request: &CredentialRequest) -> String {
+    qualified_account(&request.client_id(), &request.login)
+}
+
+fn qualified_account(client_id: &str, login: &str) -> String {
+    if login.is_empty() {
+        String::from(client_id)
+    } else {
+        format!("{}:{}", client_id, login)
+    }
+}
+
+/// Stores keeping one entry per account name, the keyring and pass. Each
+/// credential is kept under `client_id:login` for the account it was issued
+/// to, so logging in to a second account doesn't replace the first. The bare
+/// client ID holds an alias naming the account last logged in without one
+/// being selected, which requests without an account follow.
+trait AccountKeyed {
+    fn read(&self, request: &CredentialRequest, account: &str) -> Option<Credential>;
+    fn write(&self, request: &CredentialRequest, account: &str, credential: &Credential) -> Result<(), Box<dyn Error>>;
+    fn remove(&self, request: &CredentialRequest, account: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Whether a stored entry only names the account to use.
+fn is_alias(credential: &Credential) -> bool {
+    credential.token.is_empty() && credential.refresh_token.is_empty() && !credential.login.is_empty()
+}
+
+fn fetch_keyed<S: AccountKeyed>(store: &S, request: &CredentialRequest) -> Option<Credential> {
+    let client_id = request.client_id();
+    if request.login.is_empty() {
+        return match store.read(request, &client_id) {
+            Some(alias) if is_alias(&alias) => store.read(request, &qualified_account(&client_id, &alias.login)),
+            stored => stored,
+        }
+    }
+
+    match store.read(request, &keychain_account(request)) {
+        Some(stored) => Some(stored),
+        // The account may have been logged in before credentials were kept
+        // per account, in which case it is stored under the bare client ID.
+        None => store.read(request, &client_id).filter(|sc| sc.login == request.login && !is_alias(sc)),
+    }
+}
+
+fn store_keyed<S: AccountKeyed>(store: &S, credential: &mut Credential, request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
     let client_id = request.client_id();
-    let keyring = Keyring::new(&request.host, &client_id);
+    credential.client_id = client_id.clone();
+    credential.host = request.host.clone();
+
+    let login = if credential.login.is_empty() { request.login.clone() } else { credential.login.clone() };
+    if login.is_empty() {
+        return store.write(request, &client_id, credential)
+    }
+    store.write(request, &qualified_account(&client_id, &login), credential)?;
+    if !request.login.is_empty() {
+        return Ok(())
+    }
+
+    // Keep an account stored under the bare client ID before credentials
+    // were kept per account, then point requests without one at this one.
+    if let Some(previous) = store.read(request, &client_id) {
+        if !is_alias(&previous) && !previous.login.is_empty() && previous.login != login
+            && store.read(request, &qualified_account(&client_id, &previous.login)).is_none() {
+            store.write(request, &qualified_account(&client_id, &previous.login), &previous)?;
+        }
+    }
+    let alias = Credential {
+        client_id: client_id.clone(),
+        host: request.host.clone(),
+        login,
+        ..Credential::default()
+    };
+    store.write(request, &client_id, &alias)
+}
+
+fn delete_keyed<S: AccountKeyed>(store: &S, request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
+    let client_id = request.client_id();
+    let bare = store.read(request, &client_id);
+    if request.login.is_empty() {
+        if let Some(alias) = bare.filter(is_alias) {
+            store.remove(request, &qualified_account(&client_id, &alias.login))?;
+        }
+        return store.remove(request, &client_id)
+    }
+
+    if store.read(request, &keychain_account(request)).is_some() {
+        store.remove(request, &keychain_account(request))?;
+    }
+    match bare {
+        Some(stored) if stored.login == request.login => store.remove(request, &client_id),
+        _ => Ok(()),
+    }
+}
+
+fn read_keychain_credential(request: &CredentialRequest, account: &str) -> Option<Credential> {
+    let keyring = Keyring::new(&request.host, account);
     let data = keyring.get_password();
 
     match data {
         Ok(d) => {
-            match serde_json::from_str(d.as_str()) {
-                Ok(stored) => Some(stored),
+            match serde_json::from_str::<Credential>(d.as_str()) {
+                Ok(mut stored) => {
+                    if stored.host.is_empty() {
+                        stored.host = request.host.clone();
+                    }
+                    Some(stored)
+                },
                 _ => None
             }
         },
@@ -22,74 +347,629 @@ fn fetch_keychain_credential(request: &CredentialRequest) -> Option<Credential>
 fn fetch_file_credential(request: &CredentialRequest) -> Option<Credential> {
     let client_id = request.client_id();
 
-    request.config.credential_for(client_id)
+    request.config.credential_for(&request.host, &client_id, &request.login)
 }
 
-pub fn fetch_credential(request: &CredentialRequest) -> Option<Credential> {
-    let keychain_result = fetch_keychain_credential(&request);
-    if keychain_result.is_some() {
-        return keychain_result
+pub fn fetch_credential(request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+    fetch_credential_from(&configured_stores(request), request)
+}
+
+/// The first credential found in `stores`. A store that can't be read is
+/// skipped, but its error is returned when no other store has the
+/// credential, rather than reporting that none is stored.
+pub fn fetch_credential_from(stores: &[Box<dyn CredentialStore>], request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+    let mut last_error: Option<Box<dyn Error>> = None;
+
+    for store in stores {
+        match store.fetch(request) {
+            Ok(Some(credential)) => {
+                util::trace("store", format!("credential found in {}", store.name()).as_str(), Some("store"));
+                return Ok(Some(credential))
+            },
+            Ok(None) => (),
+            Err(e) => {
+                util::trace("store", format!("unable to read {}: {}", store.name(), e).as_str(), Some("store"));
+                last_error = Some(e);
+            },
+        }
     }
 
-    fetch_file_credential(&request)
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(None),
+    }
 }
 
-fn store_keychain_credential(credential: &mut Credential, request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
+fn store_file_credential(credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
     let client_id = request.client_id();
-    credential.client_id = client_id.clone();
+    credential.client_id = client_id;
+    credential.host = request.host.clone();
+    // Like the keyed stores, only a request without an account moves the
+    // default to the credential's account.
+    request.config.store_credential(credential, request.login.is_empty())
+}
 
-    let credentials_json = serde_json::to_string(&credential)?;
+pub fn store_credential(credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    store_credential_with(&configured_stores(request), credential, request)
+}
 
-    let keyring = Keyring::new(&request.host, &client_id);
+/// Store a credential in `stores`, keeping its access token in the access
+/// token cache instead when that is enabled.
+pub fn store_credential_with(stores: &[Box<dyn CredentialStore>], credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    if !request.config.caches_access_tokens() {
+        return store_credential_in(stores, credential, request)
+    }
 
-    match keyring.set_password(&credentials_json) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Box::new(e)),
+    let mut long_lived = long_lived(credential, request);
+    store_credential_in(stores, &mut long_lived, request)?;
+    credential.client_id = long_lived.client_id;
+    credential.host = long_lived.host;
+
+    cache_access_token(credential, request)
+}
+
+pub fn store_credential_in(stores: &[Box<dyn CredentialStore>], credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    let mut last_error: Box<dyn Error> = util::credential_error("No storage backends configured");
+
+    let mut failed: Vec<&str> = Vec::new();
+
+    for store in stores {
+        match store.store(credential, request) {
+            Ok(_) => {
+                util::trace("store", format!("credential stored in {}", store.name()).as_str(), Some("store"));
+                if !failed.is_empty() {
+                    eprintln!("warning: unable to store credential in {}, stored it in {} instead", failed.join(", "), store.name());
+                }
+                return Ok(())
+            },
+            Err(e) => {
+                util::trace("store", format!("unable to store credential in {}: {}", store.name(), e).as_str(), Some("store"));
+                failed.push(store.name());
+                last_error = e;
+            },
+        }
     }
+
+    Err(last_error)
 }
 
-fn store_file_credential(credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
-    let client_id = request.client_id();
-    credential.client_id = client_id;
-    match request.config.store_credential(credential) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Box::new(e))
+fn delete_file_credential(request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    request.delete_credential()
+}
+
+pub fn delete_credential(request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    if request.config.caches_access_tokens() {
+        evict_access_token(request)?;
     }
+
+    let stores = configured_stores(request);
+    delete_credential_from(&stores, request)
 }
 
-pub fn store_credential(credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
-    let keychain_result = store_keychain_credential(credential, request);
-    if keychain_result.is_ok() {
-        return keychain_result
+/// Delete the credential from every store holding it, so a rejected token
+/// isn't served again from a later store. Stores without it are skipped.
+pub fn delete_credential_from(stores: &[Box<dyn CredentialStore>], request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    let mut last_error: Option<Box<dyn Error>> = None;
+
+    for store in stores {
+        if let Ok(None) = store.fetch(request) {
+            util::trace("store", format!("no credential to delete in {}", store.name()).as_str(), Some("store"));
+            continue
+        }
+        match store.delete(request) {
+            Ok(_) => util::trace("store", format!("credential deleted from {}", store.name()).as_str(), Some("store")),
+            Err(e) => {
+                util::trace("store", format!("unable to delete credential from {}: {}", store.name(), e).as_str(), Some("store"));
+                last_error = Some(e);
+            },
+        }
     }
 
-    store_file_credential(credential, request)
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
-fn delete_keychain_credential(request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
-    let client_id = request.client_id();
-    let host = request.host.clone();
-    let keyring = keyring::Keyring::new(&host, &client_id);
+/// The form of a credential kept in the configured stores. When access
+/// tokens are cached separately only the refresh token is kept there, so
+/// the stores are only written when the refresh token changes. Tokens of
+/// apps with token expiration disabled can't be refreshed, so they are
+/// kept whole.
+pub fn long_lived(credential: &Credential, request: &CredentialRequest) -> Credential {
+    let mut long_lived = credential.clone();
+    if request.config.caches_access_tokens() && !credential.refresh_token.is_empty() {
+        long_lived.token = String::new();
+        long_lived.expiry = None;
+    }
+    long_lived
+}
+
+/// A cached, unexpired access token for a request, without its refresh
+/// token. Only consulted when `access_token_cache` is enabled.
+pub fn fetch_cached_access_token(request: &CredentialRequest) -> Option<Credential> {
+    if !request.config.caches_access_tokens() {
+        return None
+    }
 
-    match keyring.delete_password() {
-        Ok(_) => Ok(()),
+    let cache = match read_access_token_cache() {
+        Ok(cache) => cache,
+        Err(e) => {
+            util::trace("store", format!("unable to read access token cache: {}", e).as_str(), Some("store"));
+            return None
+        }
+    };
+
+    cache.get(&access_token_key(request))
+        .filter(|cached| !cached.token.is_empty() && !cached.expires_within(request.config.refresh_margin()))
+        .cloned()
+}
+
+pub fn cache_access_token(credential: &Credential, request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
+    let mut cached = credential.clone();
+    cached.refresh_token = String::new();
+
+    update_access_token_cache(|cache| {
+        cache.insert(access_token_key(request), cached.clone()).as_ref() != Some(&cached)
+    })
+}
+
+fn evict_access_token(request: &CredentialRequest) -> Result<(), Box<dyn Error>> {
+    update_access_token_cache(|cache| cache.remove(&access_token_key(request)).is_some())
+}
+
+/// Apply `change` to the access token cache under its lock, writing it back
+/// only when `change` reports it modified the cache.
+fn update_access_token_cache<F: FnOnce(&mut HashMap<String, Credential>) -> bool>(change: F) -> Result<(), Box<dyn Error>> {
+    let lock = exclusive_lock("access-tokens.lock")?;
+    let mut cache = read_access_token_cache().unwrap_or_default();
+    if change(&mut cache) {
+        write_access_token_cache(&cache)?;
+    }
+    drop(lock);
+    Ok(())
+}
+
+fn access_token_key(request: &CredentialRequest) -> String {
+    format!("{}/{}", request.host, keychain_account(request))
+}
+
+fn access_token_cache_path() -> Result<PathBuf, Box<dyn Error>> {
+    let config_path = confy::get_configuration_file_path("github-keychain", None)?;
+    Ok(config_path.with_file_name("access-tokens.json"))
+}
+
+fn read_access_token_cache() -> Result<HashMap<String, Credential>, Box<dyn Error>> {
+    match fs::read_to_string(access_token_cache_path()?) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
         Err(e) => Err(Box::new(e)),
     }
 }
 
-fn delete_file_credential(request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
-    match request.delete_credential() {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Box::new(e))
+fn write_access_token_cache(cache: &HashMap<String, Credential>) -> Result<(), Box<dyn Error>> {
+    write_private_file(&access_token_cache_path()?, serde_json::to_string(cache)?.as_bytes())
+}
+
+/// Take an exclusive lock on the file `name` next to the configuration file,
+/// waiting for any other process holding it. The lock is released when the
+/// returned file is dropped.
+pub fn exclusive_lock(name: &str) -> Result<fs::File, Box<dyn Error>> {
+    let config_path = confy::get_configuration_file_path("github-keychain", None)?;
+    let path = config_path.with_file_name(name);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
     }
+
+    let file = fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+    file.lock()?;
+    Ok(file)
 }
 
-pub fn delete_credential(request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
-    let keychain_result = delete_keychain_credential(request);
+/// Write a file readable only by the current user, creating its directory.
+/// The contents are written to a temporary file and renamed into place, so
+/// a concurrent reader never sees a partly written file.
+pub(crate) fn write_private_file(path: &Path, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let written = options.open(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|_| fs::rename(&temp_path, path)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(Box::new(e))
+    }
+
+    Ok(())
+}
+
+fn pass_entry(request: &CredentialRequest, account: &str) -> String {
+    format!("{}/{}/{}", request.config.pass_prefix(), request.host, account)
+}
+
+fn pass_command(request: &CredentialRequest) -> Command {
+    Command::new(request.config.pass_command())
+}
+
+fn check_pass_status(status: std::process::ExitStatus, operation: &str, entry: &str) -> Result<(), Box<dyn Error>> {
+    if status.success() {
+        Ok(())
+    } else {
+        let err = format!("pass {} {} failed with {}", operation, entry, status);
+        Err(util::credential_error(err.as_str()))
+    }
+}
 
-    if keychain_result.is_ok() {
-        return keychain_result
+#[derive(Serialize, Deserialize, Debug)]
+struct EncryptedCredentials {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn encrypted_credentials_path() -> Result<PathBuf, Box<dyn Error>> {
+    let config_path = confy::get_configuration_file_path("github-keychain", None)?;
+    Ok(config_path.with_file_name("credentials.enc"))
+}
+
+fn encryption_key_material(request: &CredentialRequest) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let Some(key_file) = request.config.encryption_key_file() {
+        return match fs::read(&key_file) {
+            Ok(material) => Ok(material),
+            Err(e) => {
+                let err = format!("Unable to read encryption key file {}: {}", key_file, e);
+                Err(util::credential_error(err.as_str()))
+            }
+        }
+    }
+
+    match env::var("GIT_KEYCHAIN_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase.into_bytes()),
+        _ => Err(util::credential_error(
+            "The encrypted-file backend is locked, set encryption_key_file or GIT_KEYCHAIN_PASSPHRASE"
+        )),
+    }
+}
+
+fn derive_key(material: &[u8], salt: &[u8]) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut key = [0u8; 32];
+    match Argon2::default().hash_password_into(material, salt, &mut key) {
+        Ok(_) => Ok(key),
+        Err(e) => Err(util::credential_error(format!("Unable to derive encryption key: {}", e).as_str())),
+    }
+}
+
+fn encrypt(material: &[u8], plaintext: &[u8]) -> Result<EncryptedCredentials, Box<dyn Error>> {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(material, &salt)?;
+
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = match cipher.encrypt(&nonce, plaintext) {
+        Ok(ciphertext) => ciphertext,
+        Err(_) => return Err(util::credential_error("Unable to encrypt credentials")),
+    };
+
+    Ok(EncryptedCredentials {
+        salt: to_hex(&salt),
+        nonce: to_hex(&nonce),
+        ciphertext: to_hex(&ciphertext),
+    })
+}
+
+fn decrypt(material: &[u8], encrypted: &EncryptedCredentials) -> Result<Vec<u8>, Box<dyn Error>> {
+    let salt = from_hex(&encrypted.salt)?;
+    let nonce = from_hex(&encrypted.nonce)?;
+    let ciphertext = from_hex(&encrypted.ciphertext)?;
+    if nonce.len() != 24 {
+        return Err(util::credential_error("Encrypted credentials have an invalid nonce"))
     }
+    let key = derive_key(material, &salt)?;
 
-    delete_file_credential(request)
+    let cipher = XChaCha20Poly1305::new(&key.into());
+    match cipher.decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice()) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(_) => Err(util::credential_error("Unable to decrypt credentials, is the passphrase or key file correct?")),
+    }
+}
+
+fn read_encrypted_credentials(request: &CredentialRequest) -> Result<Vec<Credential>, Box<dyn Error>> {
+    let path = encrypted_credentials_path()?;
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    let encrypted: EncryptedCredentials = serde_json::from_str(&contents)?;
+    let plaintext = decrypt(&encryption_key_material(request)?, &encrypted)?;
+
+    Ok(serde_json::from_slice(&plaintext)?)
+}
+
+/// Apply `change` to the encrypted credentials under a lock, so concurrent
+/// git processes don't lose each other's updates.
+fn update_encrypted_credentials<F: FnOnce(&mut Vec<Credential>)>(request: &CredentialRequest, change: F) -> Result<(), Box<dyn Error>> {
+    let lock = exclusive_lock("credentials.lock")?;
+
+    let mut creds = read_encrypted_credentials(request)?;
+    change(&mut creds);
+
+    let plaintext = serde_json::to_vec(&creds)?;
+    let encrypted = encrypt(&encryption_key_material(request)?, &plaintext)?;
+    write_private_file(&encrypted_credentials_path()?, serde_json::to_string(&encrypted)?.as_bytes())?;
+
+    drop(lock);
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        return Err(util::credential_error("Invalid hex in encrypted credentials"))
+    }
+    let mut bytes = Vec::with_capacity(hex.len() / 2);
+    for i in (0..hex.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&hex[i..i + 2], 16)?);
+    }
+    Ok(bytes)
+}
+
+/// A store keeping credentials in memory, standing in for one provided
+/// by another tool.
+#[cfg(test)]
+pub(crate) struct MemoryStore(std::cell::RefCell<Vec<Credential>>);
+
+#[cfg(test)]
+impl MemoryStore {
+    pub(crate) fn holding(credential: Credential) -> Box<dyn CredentialStore> {
+        Box::new(MemoryStore(std::cell::RefCell::new(vec![credential])))
+    }
+}
+
+#[cfg(test)]
+impl CredentialStore for MemoryStore {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn fetch(&self, request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+        let client_id = request.client_id();
+        Ok(self.0.borrow().iter().find(|sc| sc.belongs_to(&request.host, &client_id, &request.login)).cloned())
+    }
+
+    fn store(&self, credential: &mut Credential, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        credential.client_id = request.client_id();
+        credential.host = request.host.clone();
+        self.0.borrow_mut().push(credential.clone());
+        Ok(())
+    }
+
+    fn delete(&self, request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+        let client_id = request.client_id();
+        self.0.borrow_mut().retain(|sc| !sc.belongs_to(&request.host, &client_id, &request.login));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// An account keyed store in memory, standing in for the keyring.
+    struct MemoryKeyed(RefCell<HashMap<String, Credential>>);
+
+    impl AccountKeyed for MemoryKeyed {
+        fn read(&self, _request: &CredentialRequest, account: &str) -> Option<Credential> {
+            self.0.borrow().get(account).cloned()
+        }
+
+        fn write(&self, _request: &CredentialRequest, account: &str, credential: &Credential) -> Result<(), Box<dyn Error>> {
+            self.0.borrow_mut().insert(String::from(account), credential.clone());
+            Ok(())
+        }
+
+        fn remove(&self, _request: &CredentialRequest, account: &str) -> Result<(), Box<dyn Error>> {
+            self.0.borrow_mut().remove(account);
+            Ok(())
+        }
+    }
+
+    fn logged_in(login: &str, refresh_token: &str) -> Credential {
+        Credential {
+            login: String::from(login),
+            refresh_token: String::from(refresh_token),
+            ..Credential::default()
+        }
+    }
+
+    fn memory_request() -> CredentialRequest {
+        let mut request = CredentialRequest::empty();
+        request.host = String::from("github.com");
+        request.username = String::from("Iv1.abc");
+        request
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_round_trip() {
+        let encrypted = encrypt(b"correct horse", b"{\"token\":\"ghu_abc\"}").unwrap();
+        assert!(!encrypted.ciphertext.contains(&to_hex(b"ghu_abc")));
+
+        let plaintext = decrypt(b"correct horse", &encrypted).unwrap();
+        assert_eq!(b"{\"token\":\"ghu_abc\"}".to_vec(), plaintext);
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_passphrase_fails() {
+        let encrypted = encrypt(b"correct horse", b"secret").unwrap();
+        assert!(decrypt(b"battery staple", &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_pass_entry() {
+        let mut request = CredentialRequest::empty();
+        request.host = String::from("ghes.example.com");
+        request.username = String::from("Iv1.abc");
+        assert_eq!("github-keychain/ghes.example.com/Iv1.abc", pass_entry(&request, &keychain_account(&request)));
+
+        request.login = String::from("octocat");
+        assert_eq!("github-keychain/ghes.example.com/Iv1.abc:octocat", pass_entry(&request, &keychain_account(&request)));
+    }
+
+    #[test]
+    fn test_long_lived_drops_access_token_only_when_caching() {
+        let credential = Credential {
+            token: String::from("ghu_abc"),
+            expiry: crate::parse_expiry("2023-12-03T04:09:59+00:00"),
+            refresh_token: String::from("ghr_abc"),
+            ..Credential::default()
+        };
+        let mut request = CredentialRequest::empty();
+        assert_eq!(credential, long_lived(&credential, &request));
+
+        request.config.access_token_cache = Some(String::from("file"));
+        let stored = long_lived(&credential, &request);
+        assert!(stored.token.is_empty());
+        assert!(stored.expiry.is_none());
+        assert_eq!("ghr_abc", stored.refresh_token);
+    }
+
+    #[test]
+    fn test_long_lived_keeps_access_token_without_refresh_token() {
+        let credential = Credential {
+            token: String::from("ghu_abc"),
+            ..Credential::default()
+        };
+        let mut request = CredentialRequest::empty();
+        request.config.access_token_cache = Some(String::from("file"));
+        assert_eq!(credential, long_lived(&credential, &request));
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(vec![0u8, 15, 255], from_hex(&to_hex(&[0, 15, 255])).unwrap());
+        assert!(from_hex("abc").is_err());
+    }
+
+    #[test]
+    fn test_write_private_file_replaces_contents() {
+        let dir = std::env::temp_dir().join(format!("github-keychain-test-{}", std::process::id()));
+        let path = dir.join("access-tokens.json");
+
+        write_private_file(&path, b"first").unwrap();
+        write_private_file(&path, b"second").unwrap();
+
+        assert_eq!("second", fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        #[cfg(unix)]
+        assert_eq!(0o600, std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_delete_credential_from_clears_every_store() {
+        let credential = Credential {
+            client_id: String::from("Iv1.abc"),
+            host: String::from("github.com"),
+            token: String::from("ghu_abc"),
+            ..Credential::default()
+        };
+        let stores = vec![MemoryStore::holding(credential.clone()), MemoryStore::holding(credential)];
+        let mut request = memory_request();
+
+        delete_credential_from(&stores, &mut request).unwrap();
+
+        assert!(fetch_credential_from(&stores, &request).unwrap().is_none());
+    }
+
+    /// A store that can't be read, like an encrypted file without its key.
+    struct LockedStore;
+
+    impl CredentialStore for LockedStore {
+        fn name(&self) -> &str {
+            "locked"
+        }
+
+        fn fetch(&self, _request: &CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+            Err(util::credential_error("The store is locked"))
+        }
+
+        fn store(&self, _credential: &mut Credential, _request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+            Err(util::credential_error("The store is locked"))
+        }
+
+        fn delete(&self, _request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+            Err(util::credential_error("The store is locked"))
+        }
+    }
+
+    #[test]
+    fn test_fetch_credential_from_reports_unreadable_store() {
+        let request = memory_request();
+        let stores: Vec<Box<dyn CredentialStore>> = vec![Box::new(LockedStore)];
+        let err = fetch_credential_from(&stores, &request).unwrap_err();
+        assert_eq!("The store is locked", err.to_string());
+
+        let credential = Credential {
+            client_id: String::from("Iv1.abc"),
+            host: String::from("github.com"),
+            token: String::from("ghu_abc"),
+            ..Credential::default()
+        };
+        let stores = vec![Box::new(LockedStore), MemoryStore::holding(credential)];
+        assert_eq!("ghu_abc", fetch_credential_from(&stores, &request).unwrap().unwrap().token);
+    }
+
+    #[test]
+    fn test_keyed_store_keeps_each_logged_in_account() {
+        let store = MemoryKeyed(RefCell::new(HashMap::new()));
+        let mut request = memory_request();
+
+        store_keyed(&store, &mut logged_in("octocat", "ghr_first"), &request).unwrap();
+        store_keyed(&store, &mut logged_in("hubot", "ghr_second"), &request).unwrap();
+
+        assert_eq!("ghr_second", fetch_keyed(&store, &request).unwrap().refresh_token);
+        request.login = String::from("octocat");
+        assert_eq!("ghr_first", fetch_keyed(&store, &request).unwrap().refresh_token);
+    }
+
+    #[test]
+    fn test_keyed_store_keeps_account_stored_under_bare_client_id() {
+        let store = MemoryKeyed(RefCell::new(HashMap::new()));
+        let mut request = memory_request();
+        store.0.borrow_mut().insert(String::from("Iv1.abc"), logged_in("octocat", "ghr_first"));
+
+        store_keyed(&store, &mut logged_in("hubot", "ghr_second"), &request).unwrap();
+
+        request.login = String::from("octocat");
+        assert_eq!("ghr_first", fetch_keyed(&store, &request).unwrap().refresh_token);
+    }
+
+    #[test]
+    fn test_keyed_store_delete_follows_alias() {
+        let store = MemoryKeyed(RefCell::new(HashMap::new()));
+        let request = memory_request();
+        store_keyed(&store, &mut logged_in("octocat", "ghr_first"), &request).unwrap();
+
+        delete_keyed(&store, &request).unwrap();
+
+        assert!(store.0.borrow().is_empty());
+    }
 }
@@ -1,12 +1,14 @@
 
-use crate::{storage, CredentialRequest, ParseError, Credential, CredentialError, github};
+use crate::storage::CredentialStore;
+use crate::{storage, CredentialRequest, ParseError, Credential, CredentialError, GithubKeychainConfig, github};
 use std::{error::Error};
 use std::io::{self, Read, Write};
+use chrono::Duration;
 use std::process::{Command, Stdio};
 
 // Parse "name=value" strings
 fn parse_line(line: String, mut input: CredentialRequest) -> Result<CredentialRequest, ParseError> {
-    let mut split = line.split("=");
+    let mut split = line.splitn(2, '=');
     // let vec = split.clone().collect::<Vec<&str>>();
     // eprintln!("splt into {:?}", split);
     if split.clone().count() < 2 {
@@ -33,7 +35,10 @@ fn parse_line(line: String, mut input: CredentialRequest) -> Result<CredentialRe
         "host" => input.host = value,
         "protocol" => input.protocol = value,
         "path" => input.path = value,
-        "password" => {},
+        "port" => input.port = value,
+        "password" => input.password = value,
+        "password_expiry_utc" => input.password_expiry_utc = value,
+        "oauth_refresh_token" => input.oauth_refresh_token = value,
         key => return Err(ParseError {reason: String::from(format!("unknown attribute: {}", key))}),
     }
     Ok(input)
@@ -43,7 +48,7 @@ pub fn read_input() -> Result<CredentialRequest, Box<dyn Error>> {
     let mut buffer = String::new();
     io::stdin().read_to_string(&mut buffer)?;
 
-    let mut input = CredentialRequest::empty();
+    let mut input = CredentialRequest::load()?;
 
     // println!("read stdin: {}", buffer);
     for line in buffer.split("\n") {
@@ -52,6 +57,7 @@ pub fn read_input() -> Result<CredentialRequest, Box<dyn Error>> {
         }
         input = parse_line(String::from(line), input)?;
     }
+    input.resolve_login();
 
     Ok(input)
 }
@@ -76,16 +82,24 @@ pub fn trace(location:&str, msg: &str, trace_lvl: Option<&str>) {
 }
 
 pub fn resolve_username(client_id: Option<&String>) -> Result<CredentialRequest, Box<dyn Error>> {
-    let mut conf = CredentialRequest::empty();
+    let mut conf = CredentialRequest::load()?;
     match client_id {
         Some(client_id) => {
+            if let Some(app_config) = conf.config.config_for_client_id(client_id) {
+                conf.host = app_config.host.unwrap_or(conf.host);
+                conf.login = app_config.login.unwrap_or_default();
+            }
             conf.username = client_id.to_owned();
             Ok(conf)
         },
         None => {
             match conf.config.default_config() {
               Some(app_config) => {
-                let mut conf = CredentialRequest::empty();
+                let mut conf = CredentialRequest::load()?;
+                if let Some(host) = app_config.host {
+                    conf.host = host;
+                }
+                conf.login = app_config.login.unwrap_or_default();
                 conf.username = app_config.client_id.to_owned();
                 Ok(conf)
               },
@@ -95,16 +109,50 @@ pub fn resolve_username(client_id: Option<&String>) -> Result<CredentialRequest,
     }
 }
 
-pub fn execute_fallback(request: CredentialRequest) -> Result<(), Box<dyn Error>> {
+pub fn execute_fallback(request: CredentialRequest, operation: &str) -> Result<(), Box<dyn Error>> {
     trace("flbck", "Attempting to execute fallback command", Some("flbck"));
 
-    let gh_conf = request.config;
-    if gh_conf.fallback.is_empty() {
+    let helpers = request.config.fallback_helpers();
+    if helpers.is_empty() {
         trace("flbck", "No fallback command configured, exiting...", Some("flbck"));
         return Ok(())
     }
 
-    let mut command_parts: Vec<&str> = gh_conf.fallback.split_whitespace().collect();
+    let mut last_error: Option<Box<dyn Error>> = None;
+
+    for helper in &helpers {
+        match run_fallback(helper, &request, operation) {
+            Ok(output) => {
+                if operation != "get" {
+                    continue
+                }
+                let answer = String::from_utf8_lossy(&output);
+                let answered = answer.lines().any(|line| {
+                    line.starts_with("password=") || line == "quit=1" || line == "quit=true"
+                });
+                if answered {
+                    trace("flbck", format!("fallback `{}` answered", helper).as_str(), Some("flbck"));
+                    io::stdout().write_all(&output)?;
+                    return Ok(())
+                }
+            },
+            Err(e) => {
+                trace("flbck", format!("fallback `{}` failed: {}", helper, e).as_str(), Some("flbck"));
+                last_error = Some(e);
+            }
+        }
+    }
+
+    // Like git, a failing `get` helper only matters when no helper answered,
+    // while `store` and `erase` report a failure once every helper has run.
+    match last_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn run_fallback(helper: &str, request: &CredentialRequest, operation: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut command_parts: Vec<&str> = helper.split_whitespace().collect();
     let mut command: Command;
 
     if command_parts[0].as_bytes()[0] == b'/' {
@@ -118,33 +166,62 @@ pub fn execute_fallback(request: CredentialRequest) -> Result<(), Box<dyn Error>
     for arg in &command_parts {
         command.arg(arg);
     }
-    command.arg("get");
+    command.arg(operation);
     command.stdin(Stdio::piped());
+    command.stdout(Stdio::piped());
 
     let trace_string = format!("fallback cmd: {:?}", command);
     trace("flbck", trace_string.as_str(), Some("flbck"));
 
-    let mut child = command.spawn().expect("failed to spawn fallback");
-    let mut stdin = child.stdin.take().expect("Failed to open stdin");
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            let err = format!("Failed to spawn fallback `{}`: {}", helper, e);
+            return Err(credential_error(err.as_str()))
+        }
+    };
 
-    stdin.write(format!("host={}\n", request.host).as_str().as_bytes())?;
-    stdin.write("protocol=https\n".as_bytes())?;
+    // Dropping stdin once the attributes are written signals EOF to the helper.
+    match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(request.attributes().as_bytes())?,
+        None => return Err(credential_error("Failed to open fallback stdin")),
+    }
 
-    trace("flbck", "Fallback command executed", Some("flbck"));
+    let output = child.wait_with_output()?;
+    trace("flbck", format!("fallback exited with {}", output.status).as_str(), Some("flbck"));
 
-    Ok(())
+    if !output.status.success() {
+        let err = format!("fallback `{}` {} failed with {}", helper, operation, output.status);
+        return Err(credential_error(err.as_str()))
+    }
+
+    Ok(output.stdout)
 }
 
 pub fn login_and_store(request: &mut CredentialRequest) -> Result<Credential, Box<dyn Error>> {
+    login_and_store_with(&storage::configured_stores(request), request)
+}
+
+/// Log in with the device flow and keep the credential in `stores`.
+pub fn login_and_store_with(stores: &[Box<dyn CredentialStore>], request: &mut CredentialRequest) -> Result<Credential, Box<dyn Error>> {
     trace("login", "Initializing device flow", Some("login"));
 
     match github::device_flow_authorization_flow(request.clone()) {
         Ok(mut credential) => {
             trace("login", "Successfully authenticated with GitHub", Some("login"));
 
-            storage::store_credential(&mut credential, request)?;
+            if !request.login.is_empty() && !credential.login.is_empty() && request.login != credential.login {
+                let err = format!("Authenticated as {} but account {} was requested", credential.login, request.login);
+                return Err(credential_error(err.as_str()))
+            }
+
+            storage::store_credential_with(stores, &mut credential, request)?;
 
-            eprintln!("Stored credentials for {}.", request.username);
+            if credential.login.is_empty() {
+                eprintln!("Stored credentials for {}.", request.username);
+            } else {
+                eprintln!("Stored credentials for {} ({}).", request.username, credential.login);
+            }
             Ok(credential)
         },
         Err(e) => {
@@ -157,17 +234,332 @@ pub fn login_and_store(request: &mut CredentialRequest) -> Result<Credential, Bo
     }
 }
 
+/// Whether there is a graphical session to open a browser or use a
+/// clipboard in, SSH sessions and CI runners usually have neither. Over SSH
+/// any display belongs to the remote machine, not the user, so it is
+/// ignored, including on macOS.
+fn has_display() -> bool {
+    if std::env::var_os("SSH_CONNECTION").is_some() || std::env::var_os("SSH_TTY").is_some() {
+        return false
+    }
+    cfg!(target_os = "macos") || std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// Open `url` with `$BROWSER`, a `:` separated list of commands, or the
+/// system's opener. Without a display, e.g. over SSH, nothing is started.
+/// Returns whether a browser was launched.
+pub fn open_in_browser(url: &str) -> bool {
+    if !has_display() {
+        return false
+    }
+    let mut browsers: Vec<String> = match std::env::var("BROWSER") {
+        Ok(browsers) => browsers.split(':').filter(|b| !b.trim().is_empty()).map(String::from).collect(),
+        Err(_) => Vec::new(),
+    };
+    browsers.push(String::from(if cfg!(target_os = "macos") { "open" } else { "xdg-open" }));
+
+    for browser in browsers {
+        let mut words = browser.split_whitespace();
+        let program = match words.next() {
+            Some(program) => program,
+            None => continue,
+        };
+        let spawned = Command::new(program)
+            .args(words)
+            .arg(url)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                trace("login", format!("failed to start browser `{}`: {}", browser, e).as_str(), Some("login"));
+                continue
+            }
+        };
+        // Openers like `xdg-open` hand the URL off and exit, so one that
+        // fails straight away couldn't open it. Anything still running is
+        // taken to be the browser.
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        match child.try_wait() {
+            Ok(Some(status)) if !status.success() => {
+                trace("login", format!("browser `{}` exited with {}", browser, status).as_str(), Some("login"));
+            },
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Put `text` on the clipboard with whichever clipboard tool is available.
+/// Returns whether one succeeded.
+pub fn copy_to_clipboard(text: &str) -> bool {
+    if !has_display() {
+        return false
+    }
+    let tools: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else {
+        &[&["wl-copy"], &["xclip", "-selection", "clipboard"], &["xsel", "--clipboard", "--input"]]
+    };
+
+    for tool in tools {
+        let child = Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                trace("login", format!("clipboard tool `{}` unavailable: {}", tool[0], e).as_str(), Some("login"));
+                continue
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            if stdin.write_all(text.as_bytes()).is_err() {
+                continue
+            }
+        }
+        if child.wait().is_ok_and(|status| status.success()) {
+            return true
+        }
+    }
+    false
+}
+
+/// Whether `credential` was issued after `other`, judged by the access token
+/// and then the refresh token expiry.
+fn is_newer(credential: &Credential, other: &Credential) -> bool {
+    (credential.expiry, credential.refresh_token_expiry) > (other.expiry, other.refresh_token_expiry)
+}
+
+/// Move every stored credential into the `target` backend, removing it from
+/// every other backend it was found in. The newest copy is the one kept,
+/// preferring the one already in `target` when they were issued together.
+pub fn migrate_storage(target: &str) -> Result<(), Box<dyn Error>> {
+    let target_store = match storage::store_named(target) {
+        Some(store) => vec![store],
+        None => {
+            let err = format!("Unknown storage backend `{}`, expected one of: {}", target, storage::BACKENDS.join(", "));
+            return Err(credential_error(err.as_str()))
+        }
+    };
+
+    let identities = CredentialRequest::load()?.config.credential_identities();
+    let mut moved = 0;
+
+    for (host, client_id, login) in identities {
+        // Reload for each step, the file backend rewrites the whole config.
+        let identity_request = || -> Result<CredentialRequest, Box<dyn Error>> {
+            let mut request = CredentialRequest::load()?;
+            request.host = host.clone();
+            request.username = client_id.clone();
+            request.login = login.clone();
+            Ok(request)
+        };
+
+        // A credential already in the target may have been refreshed since
+        // the other copies were written, and refresh tokens are single use,
+        // so the newest copy is kept, preferring the target's on a tie.
+        let mut newest = storage::fetch_credential_from(&target_store, &identity_request()?)?.map(|c| (String::from(target), c));
+        let mut sources = Vec::new();
+        for source in storage::BACKENDS.iter().filter(|name| **name != target) {
+            let source_store = vec![storage::store_named(source).unwrap()];
+            let credential = match storage::fetch_credential_from(&source_store, &identity_request()?) {
+                Ok(Some(credential)) => credential,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("warning: unable to read {}, leaving its credentials in place: {}", source, e);
+                    continue
+                },
+            };
+            sources.push(source_store);
+            if newest.as_ref().is_none_or(|(_, kept)| is_newer(&credential, kept)) {
+                newest = Some((String::from(*source), credential));
+            }
+        }
+
+        match newest {
+            Some((from, mut credential)) if from != target => {
+                trace("migrt", format!("moving {} on {} from {} to {}", client_id, host, from, target).as_str(), Some("migrt"));
+                storage::store_credential_in(&target_store, &mut credential, &mut identity_request()?)?;
+                eprintln!("Moved credential for {} on {} from {} to {}.", client_id, host, from, target);
+                moved += 1;
+            },
+            Some(_) if !sources.is_empty() => {
+                eprintln!("Kept the newer credential for {} on {} already in {}.", client_id, host, target);
+            },
+            _ => {},
+        }
+
+        for source_store in sources {
+            storage::delete_credential_from(&source_store, &mut identity_request()?)?;
+        }
+    }
+
+    eprintln!(
+        "warning: keyring and pass entries can't be listed, so only accounts and hosts recorded in the configuration file were migrated; \
+        run `git-credential-github-keychain login` again for any others"
+    );
+    eprintln!("Moved {} credential(s) to {}.", moved, target);
+    Ok(())
+}
+
+pub fn store_request_credential(request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    store_request_credential_with(&storage::configured_stores(request), request)
+}
+
+/// Apply the credential git hands back during `store` to the one in `stores`.
+pub fn store_request_credential_with(stores: &[Box<dyn CredentialStore>], request: &mut CredentialRequest) -> Result<(), Box<dyn Error>> {
+    trace("store", "Updating stored credential from git", Some("store"));
+
+    if request.password.is_empty() {
+        trace("store", "No password received, nothing to store", Some("store"));
+        return Ok(())
+    }
+
+    // git stores the credential after every successful request, so skip
+    // the keychain entirely when it hands back the token already cached.
+    let cached = storage::fetch_cached_access_token(request);
+    if request.oauth_refresh_token.is_empty() && cached.is_some_and(|cached| cached.token == request.password) {
+        trace("store", "Cached access token is up to date", Some("store"));
+        return Ok(())
+    }
+
+    let stored = storage::fetch_credential_from(stores, request)?;
+    let mut credential = match stored.clone() {
+        Some(sc) => sc,
+        None => Credential::empty(),
+    };
+    credential.update_from_request(request);
+
+    if stored.is_some_and(|sc| sc == storage::long_lived(&credential, request)) {
+        if request.config.caches_access_tokens() {
+            storage::cache_access_token(&credential, request)?;
+        }
+        trace("store", "Stored credential is up to date", Some("store"));
+        return Ok(())
+    }
+
+    request.username = request.client_id();
+    storage::store_credential_with(stores, &mut credential, request)?;
+
+    trace("store", "Stored credential updated", Some("store"));
+    Ok(())
+}
+
+/// Whether git may prompt the user, so starting a device flow from `get`
+/// is worthwhile.
+pub fn is_interactive(config: &GithubKeychainConfig) -> bool {
+    if config.non_interactive() {
+        return false
+    }
+    !std::env::var("GIT_TERMINAL_PROMPT").is_ok_and(|val| val == "0")
+}
+
+fn reauthenticate(stores: &[Box<dyn CredentialStore>], request: &mut CredentialRequest, reason: &str) -> Result<Credential, Box<dyn Error>> {
+    if !is_interactive(&request.config) {
+        let err = format!(
+            "{} for {} on {} and git is not interactive, run `git-credential-github-keychain login {}` to sign in again",
+            reason, request.username, request.host, request.username
+        );
+        return Err(credential_error(err.as_str()))
+    }
+
+    eprintln!("{}, re-authenticating...", reason);
+    login_and_store_with(stores, request)
+}
+
+/// After a failed refresh, keep serving an access token that hasn't expired
+/// yet, e.g. when the refresh was attempted early and GitHub couldn't be
+/// reached, and only sign in again once it has.
+fn refresh_failed(stores: &[Box<dyn CredentialStore>], request: &mut CredentialRequest, current: Credential) -> Result<Credential, Box<dyn Error>> {
+    if !current.is_expired() && !current.token.is_empty() {
+        eprintln!(
+            "warning: unable to refresh the access token for {} on {}, using it until it expires",
+            request.username, request.host
+        );
+        return Ok(current)
+    }
+
+    reauthenticate(stores, request, "The refresh token was rejected")
+}
+
+fn warn_refresh_token_expiring(credential: &Credential, request: &CredentialRequest) {
+    let expiry = match credential.refresh_token_expiry {
+        Some(expiry) => expiry,
+        None => return
+    };
+    if credential.refresh_token_expires_within(Duration::zero()) {
+        return
+    }
+    if credential.refresh_token_expires_within(request.config.refresh_token_warning()) {
+        eprintln!(
+            "warning: the refresh token for {} on {} expires at {}, run `git-credential-github-keychain login {}` to renew it",
+            credential.client_id, credential.host, expiry.to_rfc3339(), credential.client_id
+        );
+    }
+}
+
 pub fn resolve_credential(credential_request: &mut CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
-    match storage::fetch_credential(&credential_request) {
+    resolve_credential_with(&storage::configured_stores(credential_request), credential_request)
+}
+
+/// Find a usable credential in `stores`, refreshing it or logging in again
+/// as needed.
+pub fn resolve_credential_with(stores: &[Box<dyn CredentialStore>], credential_request: &mut CredentialRequest) -> Result<Option<Credential>, Box<dyn Error>> {
+    if let Some(cached) = storage::fetch_cached_access_token(credential_request) {
+        trace("reslv", "Valid cached access token found", Some("reslv"));
+        warn_refresh_token_expiring(&cached, credential_request);
+
+        return Ok(Some(cached))
+    }
+
+    match storage::fetch_credential_from(stores, credential_request)? {
         Some(sc) => {
-            if !sc.is_expired() {
+            warn_refresh_token_expiring(&sc, credential_request);
+
+            // Without an access token, e.g. when it is cached separately and
+            // the cache was cleared, the refresh token gets us a new one.
+            let margin = credential_request.config.refresh_margin();
+            if !sc.expires_within(margin) && !sc.token.is_empty() {
                 trace("reslv", "Valid credential found", Some("reslv"));
 
                 Ok(Some(sc))
             } else {
-                trace("reslv", "Expired credential found, attempting to refresh", Some("reslv"));
+                if sc.is_expired() {
+                    trace("reslv", "Expired credential found, attempting to refresh", Some("reslv"));
+                } else {
+                    trace("reslv", "Credential expires soon, attempting to refresh", Some("reslv"));
+                }
+
+                if sc.refresh_token_expires_within(Duration::zero()) {
+                    trace("reslv", "Refresh token has expired", Some("reslv"));
+                    credential_request.username = credential_request.client_id();
+                    return reauthenticate(stores, credential_request, "The refresh token has expired").map(Some)
+                }
+
+                // Refresh tokens are single use, so only one git process may
+                // refresh at a time and the others pick up its result.
+                let refresh_lock = storage::exclusive_lock("refresh.lock")?;
+                trace("reslv", "Acquired refresh lock", Some("reslv"));
 
-                let mut cr = sc.clone();
+                credential_request.config = GithubKeychainConfig::load()?;
+                if let Some(cached) = storage::fetch_cached_access_token(credential_request) {
+                    trace("reslv", "Access token was refreshed by another process", Some("reslv"));
+                    return Ok(Some(cached))
+                }
+                let mut cr = match storage::fetch_credential_from(stores, credential_request)? {
+                    Some(latest) if !latest.expires_within(margin) && !latest.token.is_empty() => {
+                        trace("reslv", "Credential was refreshed by another process", Some("reslv"));
+                        return Ok(Some(latest))
+                    },
+                    Some(latest) => latest,
+                    None => sc.clone(),
+                };
                 credential_request.username = credential_request.client_id();
 
                 match github::refresh_credential(&mut cr, credential_request) {
@@ -175,19 +567,16 @@ pub fn resolve_credential(credential_request: &mut CredentialRequest) -> Result<
                         trace("reslv", "Refreshed credential received", Some("reslv"));
 
                         let mut crr = cred.clone();
-                        storage::store_credential(&mut crr, credential_request)?;
+                        storage::store_credential_with(stores, &mut crr, credential_request)?;
                         Ok(Some(cred))
                     },
                     Err(e) => {
+                        drop(refresh_lock);
                         trace("reslv", "Error refreshing credential", Some("reslv"));
                         let err = format!("err: {:?}", e);
                         trace("reslv", err.as_str(), Some("reslv"));
 
-                        eprintln!("Error using refresh token, re-authenticating...");
-                        match login_and_store(credential_request) {
-                            Ok(c) => Ok(Some(c)),
-                            Err(e) => Err(e)
-                        }
+                        refresh_failed(stores, credential_request, cr).map(Some)
                     }
                 }
             }
@@ -195,10 +584,7 @@ pub fn resolve_credential(credential_request: &mut CredentialRequest) -> Result<
         None => {
             credential_request.username = credential_request.client_id();
             if credential_request.is_configured() {
-                match login_and_store(credential_request) {
-                    Ok(c) => Ok(Some(c)),
-                    Err(e) => Err(e)
-                }
+                reauthenticate(stores, credential_request, "No credential is stored").map(Some)
             } else {
                 Ok(None)
             }
@@ -235,4 +621,86 @@ mod tests {
         let line = "capabilities[]=foo".to_string();
         input = parse_line(line, input).unwrap();
     }
+
+    #[test]
+    fn test_parse_line_store_attributes() {
+        let mut input = CredentialRequest::empty();
+        let line = "password=ghu_abc=".to_string();
+        input = parse_line(line, input).unwrap();
+        assert_eq!(input.password, "ghu_abc=");
+
+        let line = "password_expiry_utc=1701576599".to_string();
+        input = parse_line(line, input).unwrap();
+        assert_eq!(input.password_expiry_utc, "1701576599");
+
+        let line = "oauth_refresh_token=ghr_abc".to_string();
+        input = parse_line(line, input).unwrap();
+        assert_eq!(input.oauth_refresh_token, "ghr_abc");
+    }
+
+    #[test]
+    fn test_reauthenticate_fails_fast_when_non_interactive() {
+        let mut request = CredentialRequest::empty();
+        request.username = String::from("Iv1.abc");
+        request.host = String::from("github.com");
+        request.config.non_interactive = Some(true);
+        assert!(!is_interactive(&request.config));
+
+        let err = reauthenticate(&[], &mut request, "No credential is stored").unwrap_err();
+        assert!(err.to_string().contains("git-credential-github-keychain login Iv1.abc"));
+    }
+
+    #[test]
+    fn test_refresh_failed_keeps_unexpired_token() {
+        let mut request = CredentialRequest::empty();
+        request.username = String::from("Iv1.abc");
+        request.host = String::from("github.com");
+        request.config.non_interactive = Some(true);
+        let mut current = Credential {
+            token: String::from("ghu_abc"),
+            refresh_token: String::from("ghr_abc"),
+            expiry: Some(chrono::Utc::now() + Duration::minutes(2)),
+            ..Credential::default()
+        };
+
+        assert_eq!("ghu_abc", refresh_failed(&[], &mut request, current.clone()).unwrap().token);
+
+        current.expiry = Some(chrono::Utc::now() - Duration::minutes(2));
+        let err = refresh_failed(&[], &mut request, current).unwrap_err();
+        assert!(err.to_string().contains("The refresh token was rejected"));
+    }
+
+    #[test]
+    fn test_resolve_credential_with_reads_given_stores() {
+        let mut request = CredentialRequest::empty();
+        request.username = String::from("Iv1.abc");
+        request.host = String::from("github.com");
+        let credential = Credential {
+            client_id: String::from("Iv1.abc"),
+            host: String::from("github.com"),
+            token: String::from("ghu_abc"),
+            expiry: Some(chrono::Utc::now() + Duration::hours(8)),
+            ..Credential::default()
+        };
+        let stores = vec![storage::MemoryStore::holding(credential)];
+
+        let resolved = resolve_credential_with(&stores, &mut request).unwrap().unwrap();
+        assert_eq!(resolved.token, "ghu_abc");
+    }
+
+    #[test]
+    fn test_is_newer_compares_expiries() {
+        let old = Credential {
+            expiry: crate::parse_expiry("2023-12-03T04:09:59+00:00"),
+            ..Credential::default()
+        };
+        let refreshed = Credential {
+            expiry: crate::parse_expiry("2023-12-03T12:09:59+00:00"),
+            ..Credential::default()
+        };
+
+        assert!(is_newer(&refreshed, &old));
+        assert!(!is_newer(&old, &refreshed));
+        assert!(!is_newer(&old, &old));
+    }
 }